@@ -0,0 +1,413 @@
+use crate::*;
+use near_sdk::PromiseResult;
+
+/// tokens of one bundle, each is transferred with its own `nft_transfer_payout`
+const MAX_BUNDLE_TOKENS: usize = 5;
+/// transfers every token to the buyer and pays out the merged royalties
+const GAS_FOR_RESOLVE_BUNDLE_PURCHASE: Gas = 160_000_000_000_000;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BundleToken {
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+    pub approval_id: U64,
+    pub token_type: TokenType,
+}
+
+/// several tokens sold as one lot for one price per currency
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Bundle {
+    pub bundle_id: U64,
+    pub owner_id: AccountId,
+    pub tokens: Vec<BundleToken>,
+    pub conditions: HashMap<Currency, U128>,
+}
+
+/// `nft_approve` msg adding the token to a bundle of the token owner, `token_type` is added
+/// by the NFT contract
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AddToBundleArgs {
+    pub bundle_id: U64,
+    pub token_type: TokenType,
+}
+
+/// `ft_transfer_call` msg buying a bundle
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BundlePurchaseArgs {
+    pub bundle_id: U64,
+}
+
+#[near_bindgen]
+impl Marketplace {
+    /// creates an empty bundle, tokens are added by approving the marketplace with `{"bundle_id": "<id>"}` msg
+    pub fn create_bundle(&mut self, sale_conditions: Vec<Price>) -> U64 {
+        self.assert_not_paused(MarketFeature::Listing);
+        let owner_id = env::predecessor_account_id();
+        let initial_storage_usage = env::storage_usage();
+
+        let mut conditions = HashMap::new();
+        for Price {
+            price,
+            ft_or_st_token_id,
+            st_symbol,
+        } in sale_conditions
+        {
+            let ft_or_st = Currency::new(ft_or_st_token_id.as_ref(), st_symbol);
+            if !self.ft_token_ids.contains(&ft_or_st) {
+                env::panic(format!("Token {} not supported by this market", ft_or_st).as_bytes());
+            }
+            let price = price.expect("Bundles are sold for a fixed price");
+            assert!(price.0 > 0, "Price must be greater than 0");
+            conditions.insert(ft_or_st, price);
+        }
+        assert!(!conditions.is_empty(), "Bundle needs a price");
+
+        let bundle_id = self.next_bundle_id;
+        self.next_bundle_id += 1;
+        let bundle = Bundle {
+            bundle_id: U64(bundle_id),
+            owner_id: owner_id.clone(),
+            tokens: vec![],
+            conditions,
+        };
+        self.bundles.insert(&bundle_id, &bundle);
+
+        add_id_by_account_id(
+            &mut self.bundles_by_owner_id,
+            &owner_id,
+            bundle_id,
+            |account_id_hash| StorageKey::BundlesByOwnerIdInner { account_id_hash },
+        );
+        self.internal_charge_storage(
+            &owner_id,
+            &ListingId::Bundle(bundle_id),
+            initial_storage_usage,
+        );
+
+        MarketEvent::BundleCreated(vec![bundle]).emit();
+        U64(bundle_id)
+    }
+
+    /// revokes the marketplace's approval of every token of the bundle
+    #[payable]
+    pub fn remove_bundle(&mut self, bundle_id: U64) {
+        assert_one_yocto();
+        let bundle = self.internal_remove_bundle(bundle_id.0);
+        assert_eq!(
+            env::predecessor_account_id(),
+            bundle.owner_id,
+            "Must be bundle owner"
+        );
+        for token in bundle.tokens {
+            nft_sale::nft_revoke(
+                token.token_id,
+                ValidAccountId::try_from(env::current_account_id()).unwrap(),
+                &token.nft_contract_id,
+                1,
+                GAS_FOR_NFT_TRANSFER,
+            );
+        }
+    }
+
+    /// NEAR is paid with the attached deposit, FT and ST from the caller's escrow
+    #[payable]
+    pub fn buy_bundle(
+        &mut self,
+        bundle_id: U64,
+        ft_or_st_token_id: ValidAccountId,
+        st_symbol: Option<String>,
+    ) -> Promise {
+        let buyer_id = env::predecessor_account_id();
+        let ft_or_st = Currency::new(ft_or_st_token_id.as_ref(), st_symbol);
+        let price = self
+            .bundles
+            .get(&bundle_id.0)
+            .expect("No bundle")
+            .conditions
+            .get(&ft_or_st)
+            .copied()
+            .expect("Not for sale in that token type");
+        self.internal_take_payment(&buyer_id, &ft_or_st, price.0, "price");
+        self.process_bundle_purchase(bundle_id.0, ft_or_st, price, buyer_id, true)
+    }
+
+    /// self callback, every token was transferred to the marketplace or the purchase is rolled back
+    #[private]
+    pub fn resolve_bundle_purchase(
+        &mut self,
+        bundle: Bundle,
+        ft_or_st: Currency,
+        buyer_id: AccountId,
+        price: U128,
+        fee: U128,
+        refund_to_escrow: bool,
+    ) -> U128 {
+        let shares = bundle_shares(price.0 - fee.0, bundle.tokens.len());
+        let mut transferred: Vec<BundleToken> = vec![];
+        let mut token_payouts: Vec<Option<Payout>> = vec![];
+        let mut payout: Payout = HashMap::new();
+        let mut valid = true;
+        for (index, token) in bundle.tokens.iter().enumerate() {
+            let token_payout = match env::promise_result(index as u64) {
+                PromiseResult::Successful(value) => {
+                    transferred.push(token.clone());
+                    near_sdk::serde_json::from_slice::<Payout>(&value).ok()
+                }
+                _ => None,
+            };
+            // TODO off by 1 e.g. payouts are fractions of 3333 + 3333 + 3333
+            // royalties above the policy are cut down as for single sales
+            let token_payout = token_payout
+                .filter(|token_payout| {
+                    let total = token_payout
+                        .values()
+                        .try_fold(0u128, |total, amount| total.checked_add(amount.0));
+                    matches!(total, Some(total) if total <= shares[index] && shares[index] - total <= 1)
+                })
+                .map(|token_payout| {
+                    self.internal_cap_royalties(
+                        &token.nft_contract_id,
+                        &bundle.owner_id,
+                        token_payout,
+                        shares[index],
+                    )
+                });
+            if let Some(token_payout) = token_payout.as_ref() {
+                for (receiver_id, amount) in token_payout {
+                    payout.entry(receiver_id.clone()).or_insert(U128(0)).0 += amount.0;
+                }
+            } else {
+                valid = false;
+            }
+            token_payouts.push(token_payout);
+        }
+        // larger payouts than `MAX_LEN_PAYOUT` are paid in pages, see settlement.rs
+        if payout.len() > MAX_LEN_SETTLEMENT_PAYOUT as usize || payout.is_empty() {
+            env::log(
+                format!(
+                    "Cannot have more than {} royalties",
+                    MAX_LEN_SETTLEMENT_PAYOUT
+                )
+                .as_bytes(),
+            );
+            valid = false;
+        }
+
+        // one purchase per token, the fee is split like the price
+        let fees = bundle_shares(fee.0, bundle.tokens.len());
+        let purchases = bundle
+            .tokens
+            .iter()
+            .zip(shares.iter().zip(fees))
+            .zip(token_payouts)
+            .map(|((token, (share, fee)), token_payout)| PurchaseData {
+                nft_contract_id: token.nft_contract_id.clone(),
+                token_id: token.token_id.clone(),
+                seller_id: bundle.owner_id.clone(),
+                buyer_id: buyer_id.clone(),
+                ft_or_st: ft_or_st.clone(),
+                price: U128(share + fee),
+                fee: U128(fee),
+                payout: token_payout.filter(|_| valid),
+            })
+            .collect();
+
+        if !valid {
+            MarketEvent::PurchaseFailed(purchases).emit();
+            // tokens already held by the marketplace go back to the seller
+            if !transferred.is_empty() {
+                deliver_tokens(bundle.owner_id.clone(), held_tokens(&transferred));
+            }
+            if refund_to_escrow {
+                self.internal_escrow_deposit(&buyer_id, &ft_or_st, price.0);
+                return U128(0);
+            }
+            if ft_or_st == Currency::Near {
+                Promise::new(buyer_id).transfer(price.0);
+            }
+            // leave function and return all FTs in ft_resolve_transfer
+            return price;
+        }
+
+        MarketEvent::PurchaseCompleted(purchases).emit();
+        for (token, share) in bundle.tokens.iter().zip(shares) {
+            self.internal_record_trade(Trade {
+                nft_contract_id: token.nft_contract_id.clone(),
+                token_id: token.token_id.clone(),
+                token_type: token.token_type.clone(),
+                seller_id: bundle.owner_id.clone(),
+                buyer_id: buyer_id.clone(),
+                ft_or_st: ft_or_st.clone(),
+                price: U128(share),
+                timestamp: U64(env::block_timestamp()),
+            });
+        }
+        deliver_tokens(buyer_id.clone(), held_tokens(&bundle.tokens));
+        self.internal_treasury_deposit(&ft_or_st, fee.0);
+        let tokens = bundle
+            .tokens
+            .iter()
+            .map(|token| format!("{}{}{}", token.nft_contract_id, DELIMETER, token.token_id))
+            .collect();
+        self.internal_pay_out(tokens, buyer_id, ft_or_st.clone(), payout);
+        if ft_or_st == Currency::Near {
+            // refund all FTs (won't be any)
+            price
+        } else {
+            // keep all FTs or STs (already transferred for payouts)
+            U128(0)
+        }
+    }
+
+    /// views
+    pub fn get_bundle(&self, bundle_id: U64) -> Option<Bundle> {
+        self.bundles.get(&bundle_id.0)
+    }
+
+    pub fn get_bundles_by_owner_id(
+        &self,
+        account_id: AccountId,
+        from_index: U64,
+        limit: U64,
+    ) -> Vec<Bundle> {
+        let bundle_ids = if let Some(bundle_ids) = self.bundles_by_owner_id.get(&account_id) {
+            bundle_ids
+        } else {
+            return vec![];
+        };
+        let keys = bundle_ids.as_vector();
+        let start = u64::from(from_index);
+        let end = min(start + u64::from(limit), bundle_ids.len());
+        (start..end)
+            .filter_map(|i| self.bundles.get(&keys.get(i).unwrap()))
+            .collect()
+    }
+}
+
+impl Marketplace {
+    /// called from `nft_on_approve`, the token owner approved the marketplace for one of their bundles
+    pub(crate) fn internal_add_to_bundle(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        owner_id: AccountId,
+        approval_id: U64,
+        args: AddToBundleArgs,
+    ) {
+        self.assert_not_paused(MarketFeature::Listing);
+        let mut bundle = self.bundles.get(&args.bundle_id.0).expect("No bundle");
+        assert_eq!(owner_id, bundle.owner_id, "Must be bundle owner");
+
+        self.internal_invalidate_listing(&nft_contract_id, &token_id);
+
+        let initial_storage_usage = env::storage_usage();
+        bundle
+            .tokens
+            .retain(|token| token.nft_contract_id != nft_contract_id || token.token_id != token_id);
+        assert!(
+            bundle.tokens.len() < MAX_BUNDLE_TOKENS,
+            "Cannot have more than {} tokens in a bundle",
+            MAX_BUNDLE_TOKENS
+        );
+        bundle.tokens.push(BundleToken {
+            nft_contract_id,
+            token_id,
+            approval_id,
+            token_type: args.token_type,
+        });
+        self.bundles.insert(&args.bundle_id.0, &bundle);
+        self.internal_charge_storage(
+            &bundle.owner_id,
+            &ListingId::Bundle(args.bundle_id.0),
+            initial_storage_usage,
+        );
+        MarketEvent::BundleUpdated(vec![bundle]).emit();
+    }
+
+    /// moves every token of the bundle to the marketplace, see `resolve_bundle_purchase`
+    pub(crate) fn process_bundle_purchase(
+        &mut self,
+        bundle_id: u64,
+        ft_or_st: Currency,
+        price: U128,
+        buyer_id: AccountId,
+        refund_to_escrow: bool,
+    ) -> Promise {
+        self.assert_not_paused(MarketFeature::Purchasing);
+        let bundle = self.internal_remove_bundle(bundle_id);
+        assert_ne!(bundle.owner_id, buyer_id, "Cannot buy your own bundle.");
+        assert!(!bundle.tokens.is_empty(), "Bundle has no tokens");
+        for token in bundle.tokens.iter() {
+            self.assert_nft_contract_allowed(&token.nft_contract_id);
+        }
+
+        // fee is the highest one among the NFT contracts of the bundle
+        let fee = bundle
+            .tokens
+            .iter()
+            .map(|token| self.internal_fee(&token.nft_contract_id, &ft_or_st, price.0))
+            .max()
+            .unwrap_or(0);
+        let shares = bundle_shares(price.0 - fee, bundle.tokens.len());
+        let transfers = bundle
+            .tokens
+            .iter()
+            .zip(shares)
+            .map(|(token, share)| {
+                nft_sale::nft_transfer_payout(
+                    env::current_account_id(),
+                    token.token_id.clone(),
+                    Some(token.approval_id),
+                    None,
+                    U128(share),
+                    &token.nft_contract_id,
+                    1,
+                    GAS_FOR_NFT_TRANSFER,
+                )
+            })
+            .reduce(|transfers, transfer| transfers.and(transfer))
+            .unwrap();
+
+        transfers.then(ext_self::resolve_bundle_purchase(
+            bundle,
+            ft_or_st,
+            buyer_id,
+            price,
+            U128(fee),
+            refund_to_escrow,
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_RESOLVE_BUNDLE_PURCHASE,
+        ))
+    }
+
+    pub(crate) fn internal_remove_bundle(&mut self, bundle_id: u64) -> Bundle {
+        let bundle = self.bundles.remove(&bundle_id).expect("No bundle");
+        self.internal_release_storage(&bundle.owner_id, &ListingId::Bundle(bundle_id));
+        remove_id_by_account_id(&mut self.bundles_by_owner_id, &bundle.owner_id, bundle_id);
+        MarketEvent::BundleRemoved(vec![bundle.clone()]).emit();
+        bundle
+    }
+}
+
+fn held_tokens(tokens: &[BundleToken]) -> Vec<HeldToken> {
+    tokens
+        .iter()
+        .map(|token| HeldToken {
+            nft_contract_id: token.nft_contract_id.clone(),
+            token_id: token.token_id.clone(),
+        })
+        .collect()
+}
+
+/// splits the bundle price between its tokens, last token gets the remainder
+fn bundle_shares(price: Balance, tokens: usize) -> Vec<Balance> {
+    let share = price / tokens as u128;
+    let mut shares = vec![share; tokens];
+    shares[tokens - 1] += price - share * tokens as u128;
+    shares
+}
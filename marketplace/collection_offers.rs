@@ -0,0 +1,219 @@
+use crate::*;
+
+/// standing offer for any token of an NFT contract, optionally of one token type only.
+/// Funds are held by the marketplace until the offer is filled or cancelled, its storage
+/// is charged to the offer owner
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CollectionOffer {
+    pub offer_id: U64,
+    pub owner_id: AccountId,
+    pub nft_contract_id: AccountId,
+    pub token_type: TokenType,
+    pub ft_or_st: Currency,
+    pub price: U128,
+}
+
+/// `nft_approve` msg filling a collection offer, the token type is read from the NFT contract
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AcceptCollectionOfferArgs {
+    pub accept_collection_offer: U64,
+}
+
+#[near_bindgen]
+impl Marketplace {
+    /// NEAR offers are paid with the attached deposit, FT and ST offers from the caller's escrow
+    #[payable]
+    pub fn make_collection_offer(
+        &mut self,
+        nft_contract_id: ValidAccountId,
+        token_type: TokenType,
+        ft_or_st_token_id: ValidAccountId,
+        st_symbol: Option<String>,
+        price: U128,
+    ) -> U64 {
+        self.assert_not_paused(MarketFeature::Bidding);
+        let owner_id = env::predecessor_account_id();
+        self.assert_nft_contract_allowed(nft_contract_id.as_ref());
+        let ft_or_st = Currency::new(ft_or_st_token_id.as_ref(), st_symbol);
+        if !self.ft_token_ids.contains(&ft_or_st) {
+            env::panic(format!("Token {} not supported by this market", ft_or_st).as_bytes());
+        }
+        assert!(price.0 > 0, "Price must be greater than 0");
+        self.internal_take_payment(&owner_id, &ft_or_st, price.0, "price");
+
+        let initial_storage_usage = env::storage_usage();
+        let offer_id = self.next_collection_offer_id;
+        self.next_collection_offer_id += 1;
+        let nft_contract_id: AccountId = nft_contract_id.into();
+        let offer = CollectionOffer {
+            offer_id: U64(offer_id),
+            owner_id: owner_id.clone(),
+            nft_contract_id: nft_contract_id.clone(),
+            token_type,
+            ft_or_st,
+            price,
+        };
+        self.collection_offers.insert(&offer_id, &offer);
+
+        add_id_by_account_id(
+            &mut self.collection_offers_by_nft_contract_id,
+            &nft_contract_id,
+            offer_id,
+            |account_id_hash| StorageKey::CollectionOffersByNFTContractIdInner { account_id_hash },
+        );
+        self.internal_charge_storage(
+            &owner_id,
+            &ListingId::CollectionOffer(offer_id),
+            initial_storage_usage,
+        );
+
+        MarketEvent::CollectionOfferMade(vec![offer]).emit();
+        U64(offer_id)
+    }
+
+    /// self callback, fills the offer once the NFT contract confirmed the approval and
+    /// the token type
+    #[private]
+    pub fn resolve_accept_collection_offer(
+        &mut self,
+        offer_id: U64,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        owner_id: AccountId,
+        approval_id: U64,
+    ) -> Promise {
+        let token_type = promise_approved_token_type(&owner_id, approval_id);
+        let offer = self.internal_remove_collection_offer(offer_id.0);
+        if offer.token_type.is_some() {
+            assert_eq!(
+                offer.token_type, token_type,
+                "Offer is for another token type"
+            );
+        }
+        MarketEvent::CollectionOfferAccepted(vec![OfferAcceptedData {
+            offer_id,
+            nft_contract_id: nft_contract_id.clone(),
+            token_id: token_id.clone(),
+            seller_id: owner_id.clone(),
+        }])
+        .emit();
+
+        self.internal_fill_offer(
+            SoldToken {
+                nft_contract_id,
+                token_id,
+                token_type,
+                seller_id: owner_id,
+                custodial: false,
+            },
+            approval_id,
+            offer.ft_or_st,
+            offer.owner_id,
+            offer.price,
+        )
+    }
+
+    /// offer owner takes back the offer, funds go to their escrow
+    #[payable]
+    pub fn cancel_collection_offer(&mut self, offer_id: U64) {
+        assert_one_yocto();
+        let offer = self.internal_remove_collection_offer(offer_id.0);
+        assert_eq!(
+            env::predecessor_account_id(),
+            offer.owner_id,
+            "Must be offer owner"
+        );
+        self.internal_escrow_deposit(&offer.owner_id, &offer.ft_or_st, offer.price.0);
+    }
+
+    /// views
+    pub fn get_collection_offer(&self, offer_id: U64) -> Option<CollectionOffer> {
+        self.collection_offers.get(&offer_id.0)
+    }
+
+    /// offers of an NFT contract, with `token_type` only the ones any token of that type can fill.
+    /// `from_index` and `limit` apply to the offers of the contract, so a page can hold fewer
+    /// than `limit` offers and the next one starts at `from_index + limit`
+    pub fn get_collection_offers(
+        &self,
+        nft_contract_id: AccountId,
+        token_type: TokenType,
+        from_index: U64,
+        limit: U64,
+    ) -> Vec<CollectionOffer> {
+        let offer_ids = if let Some(offer_ids) = self
+            .collection_offers_by_nft_contract_id
+            .get(&nft_contract_id)
+        {
+            offer_ids
+        } else {
+            return vec![];
+        };
+        let keys = offer_ids.as_vector();
+        let start = u64::from(from_index);
+        let end = min(start + u64::from(limit), keys.len());
+        (start..end)
+            .filter_map(|i| self.collection_offers.get(&keys.get(i).unwrap()))
+            .filter(|offer| {
+                token_type.is_none() || offer.token_type.is_none() || offer.token_type == token_type
+            })
+            .collect()
+    }
+}
+
+impl Marketplace {
+    /// called from `nft_on_approve`, the owner of `token_id` approved the marketplace to fill
+    /// the offer. The offer is filled by `resolve_accept_collection_offer` after reading the token
+    pub(crate) fn internal_accept_collection_offer(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        owner_id: AccountId,
+        approval_id: U64,
+        args: AcceptCollectionOfferArgs,
+    ) -> Promise {
+        let offer = self
+            .collection_offers
+            .get(&args.accept_collection_offer.0)
+            .expect("No collection offer");
+        assert_eq!(
+            offer.nft_contract_id, nft_contract_id,
+            "Offer is for another NFT contract"
+        );
+        assert_ne!(offer.owner_id, owner_id, "Cannot accept your own offer");
+
+        nft_sale::nft_token(
+            token_id.clone(),
+            &nft_contract_id,
+            NO_DEPOSIT,
+            GAS_FOR_NFT_TOKEN,
+        )
+        .then(ext_self::resolve_accept_collection_offer(
+            args.accept_collection_offer,
+            nft_contract_id,
+            token_id,
+            owner_id,
+            approval_id,
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_RESOLVE_ACCEPT_OFFER,
+        ))
+    }
+
+    pub(crate) fn internal_remove_collection_offer(&mut self, offer_id: u64) -> CollectionOffer {
+        let offer = self
+            .collection_offers
+            .remove(&offer_id)
+            .expect("No collection offer");
+        remove_id_by_account_id(
+            &mut self.collection_offers_by_nft_contract_id,
+            &offer.nft_contract_id,
+            offer_id,
+        );
+        self.internal_release_storage(&offer.owner_id, &ListingId::CollectionOffer(offer_id));
+        MarketEvent::CollectionOfferRemoved(vec![offer.clone()]).emit();
+        offer
+    }
+}
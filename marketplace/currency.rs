@@ -0,0 +1,58 @@
+use crate::*;
+use near_sdk::borsh;
+use near_sdk::serde::{Deserializer, Serializer};
+use std::fmt;
+
+/// currency of a price, bid or payout. JSON uses its key, `near`, the FT account id or
+/// `<ST account id>||<ST symbol>`
+#[derive(BorshDeserialize, BorshSerialize, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+pub enum Currency {
+    Near,
+    Ft(AccountId),
+    St(AccountId, String),
+}
+
+impl Currency {
+    pub fn new(ft_or_st_token_id: &str, st_symbol: Option<String>) -> Self {
+        match st_symbol {
+            Some(st_symbol) => Currency::St(ft_or_st_token_id.to_string(), st_symbol),
+            None if ft_or_st_token_id == NEAR => Currency::Near,
+            None => Currency::Ft(ft_or_st_token_id.to_string()),
+        }
+    }
+
+    /// parses the key of a currency, see `fmt::Display`
+    pub fn from_key(ft_or_st: &str) -> Self {
+        let mut parts = ft_or_st.splitn(2, DELIMETER);
+        let ft_or_st_token_id = parts.next().unwrap();
+        Currency::new(
+            ft_or_st_token_id,
+            parts.next().map(|st_symbol| st_symbol.to_string()),
+        )
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Currency::Near => write!(f, "{}", NEAR),
+            Currency::Ft(ft_token_id) => write!(f, "{}", ft_token_id),
+            Currency::St(st_token_id, st_symbol) => {
+                write!(f, "{}{}{}", st_token_id, DELIMETER, st_symbol)
+            }
+        }
+    }
+}
+
+impl Serialize for Currency {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Currency {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let ft_or_st: String = Deserialize::deserialize(deserializer)?;
+        Ok(Currency::from_key(&ft_or_st))
+    }
+}
@@ -0,0 +1,110 @@
+use crate::*;
+use near_sdk::PromiseResult;
+
+/// tokens transferred by one `claim_tokens` call
+const MAX_CLAIMED_TOKENS: usize = 5;
+const GAS_FOR_RESOLVE_DELIVERY: Gas = 5_000_000_000_000;
+
+// tokens the marketplace holds in custody for bundles and swaps are delivered with
+// `nft_transfer`. A token whose transfer fails stays with the marketplace and is recorded
+// for its receiver, who gets it with `claim_tokens`
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct HeldToken {
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+}
+
+#[near_bindgen]
+impl Marketplace {
+    /// transfers up to `MAX_CLAIMED_TOKENS` tokens the marketplace couldn't deliver to the caller
+    pub fn claim_tokens(&mut self) -> Promise {
+        let receiver_id = env::predecessor_account_id();
+        let mut tokens = self
+            .undelivered_tokens
+            .get(&receiver_id)
+            .expect("No undelivered tokens");
+        let claimed: Vec<HeldToken> = tokens
+            .drain(..min(MAX_CLAIMED_TOKENS, tokens.len()))
+            .collect();
+        if tokens.is_empty() {
+            self.undelivered_tokens.remove(&receiver_id);
+        } else {
+            self.undelivered_tokens.insert(&receiver_id, &tokens);
+        }
+        deliver_tokens(receiver_id, claimed)
+    }
+
+    /// self callback, records the tokens that could not be transferred to `receiver_id`.
+    /// Returns the undelivered tokens
+    #[private]
+    pub fn resolve_delivery(
+        &mut self,
+        receiver_id: AccountId,
+        tokens: Vec<HeldToken>,
+    ) -> Vec<HeldToken> {
+        let undelivered: Vec<HeldToken> = tokens
+            .into_iter()
+            .enumerate()
+            .filter(|(index, _)| {
+                !matches!(
+                    env::promise_result(*index as u64),
+                    PromiseResult::Successful(_)
+                )
+            })
+            .map(|(_, token)| {
+                env::log(
+                    format!(
+                        "Could not transfer {}{}{} to {}",
+                        token.nft_contract_id, DELIMETER, token.token_id, receiver_id
+                    )
+                    .as_bytes(),
+                );
+                token
+            })
+            .collect();
+        if !undelivered.is_empty() {
+            let mut tokens = self
+                .undelivered_tokens
+                .get(&receiver_id)
+                .unwrap_or_default();
+            tokens.extend(undelivered.iter().cloned());
+            self.undelivered_tokens.insert(&receiver_id, &tokens);
+        }
+        undelivered
+    }
+
+    /// views
+    pub fn get_undelivered_tokens(&self, account_id: ValidAccountId) -> Vec<HeldToken> {
+        self.undelivered_tokens
+            .get(account_id.as_ref())
+            .unwrap_or_default()
+    }
+}
+
+/// transfers tokens held by the marketplace to `receiver_id`, see `resolve_delivery`
+pub(crate) fn deliver_tokens(receiver_id: AccountId, tokens: Vec<HeldToken>) -> Promise {
+    let transfers = tokens
+        .iter()
+        .map(|token| {
+            nft_sale::nft_transfer(
+                receiver_id.clone(),
+                token.token_id.clone(),
+                None,
+                None,
+                &token.nft_contract_id,
+                1,
+                GAS_FOR_NFT_TRANSFER,
+            )
+        })
+        .reduce(|transfers, transfer| transfers.and(transfer))
+        .unwrap();
+    transfers.then(ext_self::resolve_delivery(
+        receiver_id,
+        tokens,
+        &env::current_account_id(),
+        NO_DEPOSIT,
+        GAS_FOR_RESOLVE_DELIVERY,
+    ))
+}
@@ -0,0 +1,183 @@
+use crate::*;
+use near_sdk::PromiseResult;
+
+const GAS_FOR_RESOLVE_WITHDRAW: Gas = 10_000_000_000_000;
+
+// internal ledger of funds held by the marketplace on behalf of accounts,
+// e.g. outbid or refunded bids. Owners pull their funds out with `withdraw`.
+// The storage of escrow balances is recorded against their owner, see storage.rs
+
+#[near_bindgen]
+impl Marketplace {
+    /// transfers the whole escrowed balance of given currency to the caller
+    #[payable]
+    pub fn withdraw(
+        &mut self,
+        ft_or_st_token_id: ValidAccountId,
+        st_symbol: Option<String>,
+    ) -> Promise {
+        assert_one_yocto();
+        let owner_id = env::predecessor_account_id();
+        let ft_or_st = Currency::new(ft_or_st_token_id.as_ref(), st_symbol);
+        let amount = self.internal_escrow_balance(&owner_id, &ft_or_st);
+        assert!(amount > 0, "Nothing to withdraw");
+        // debit optimistically, `resolve_withdraw` credits it back if transfer fails
+        self.internal_escrow_withdraw(&owner_id, &ft_or_st, amount);
+        transfer_ft_or_st(&ft_or_st, owner_id.clone(), U128(amount)).then(
+            ext_self::resolve_withdraw(
+                owner_id,
+                ft_or_st,
+                U128(amount),
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_RESOLVE_WITHDRAW,
+            ),
+        )
+    }
+
+    /// self callback, returns the amount that was actually withdrawn
+    #[private]
+    pub fn resolve_withdraw(
+        &mut self,
+        owner_id: AccountId,
+        ft_or_st: Currency,
+        amount: U128,
+    ) -> U128 {
+        match env::promise_result(0) {
+            PromiseResult::Successful(_) => amount,
+            _ => {
+                env::log(
+                    format!(
+                        "Withdraw of {} {} failed, returned to escrow of {}",
+                        amount.0, ft_or_st, owner_id
+                    )
+                    .as_bytes(),
+                );
+                self.internal_escrow_deposit(&owner_id, &ft_or_st, amount.0);
+                U128(0)
+            }
+        }
+    }
+
+    /// views
+    pub fn get_escrow_balance(
+        &self,
+        account_id: ValidAccountId,
+        ft_or_st_token_id: ValidAccountId,
+        st_symbol: Option<String>,
+    ) -> U128 {
+        U128(self.internal_escrow_balance(
+            account_id.as_ref(),
+            &Currency::new(ft_or_st_token_id.as_ref(), st_symbol),
+        ))
+    }
+
+    pub fn get_escrow_balances(&self, account_id: ValidAccountId) -> HashMap<Currency, U128> {
+        self.escrow
+            .get(account_id.as_ref())
+            .map(|balances| {
+                balances
+                    .iter()
+                    .map(|(ft_or_st, balance)| (ft_or_st, U128(balance)))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+impl Marketplace {
+    pub(crate) fn internal_escrow_balance(
+        &self,
+        account_id: &AccountId,
+        ft_or_st: &Currency,
+    ) -> Balance {
+        self.escrow
+            .get(account_id)
+            .and_then(|balances| balances.get(ft_or_st))
+            .unwrap_or(0)
+    }
+
+    pub(crate) fn internal_escrow_deposit(
+        &mut self,
+        account_id: &AccountId,
+        ft_or_st: &Currency,
+        amount: Balance,
+    ) {
+        if amount == 0 {
+            return;
+        }
+        let initial_storage_usage = env::storage_usage();
+        let mut balances = self.escrow.get(account_id).unwrap_or_else(|| {
+            UnorderedMap::new(
+                StorageKey::EscrowInner {
+                    account_id_hash: hash_account_id(account_id),
+                }
+                .try_to_vec()
+                .unwrap(),
+            )
+        });
+        let balance = balances.get(ft_or_st).unwrap_or(0);
+        balances.insert(
+            ft_or_st,
+            &balance
+                .checked_add(amount)
+                .expect("Escrow balance overflow"),
+        );
+        self.escrow.insert(account_id, &balances);
+        self.internal_record_storage(
+            account_id,
+            &ListingId::Escrow(account_id.clone()),
+            initial_storage_usage,
+        );
+    }
+
+    /// takes `amount` for an order of `account_id`: NEAR is the attached deposit, FT and ST
+    /// come from their escrow. `what` names the amount in the panic message
+    pub(crate) fn internal_take_payment(
+        &mut self,
+        account_id: &AccountId,
+        ft_or_st: &Currency,
+        amount: Balance,
+        what: &str,
+    ) {
+        if ft_or_st == &Currency::Near {
+            assert_eq!(
+                env::attached_deposit(),
+                amount,
+                "Attached deposit must be equal to the {}",
+                what
+            );
+        } else {
+            assert_one_yocto();
+            self.internal_escrow_withdraw(account_id, ft_or_st, amount);
+        }
+    }
+
+    pub(crate) fn internal_escrow_withdraw(
+        &mut self,
+        account_id: &AccountId,
+        ft_or_st: &Currency,
+        amount: Balance,
+    ) {
+        let initial_storage_usage = env::storage_usage();
+        let mut balances = self.escrow.get(account_id).expect("No escrow balance");
+        let balance = balances.get(ft_or_st).unwrap_or(0);
+        let balance = balance
+            .checked_sub(amount)
+            .expect("Not enough escrow balance");
+        if balance == 0 {
+            balances.remove(ft_or_st);
+        } else {
+            balances.insert(ft_or_st, &balance);
+        }
+        let listing_id = ListingId::Escrow(account_id.clone());
+        if balances.is_empty() {
+            self.escrow.remove(account_id);
+            self.internal_release_storage(account_id, &listing_id);
+        } else {
+            self.escrow.insert(account_id, &balances);
+            let freed = initial_storage_usage.saturating_sub(env::storage_usage());
+            self.internal_release_storage_bytes(account_id, &listing_id, freed);
+        }
+    }
+}
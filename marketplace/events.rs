@@ -0,0 +1,152 @@
+use crate::*;
+
+const EVENT_STANDARD: &str = "nft_marketplace";
+const EVENT_VERSION: &str = "1.0.0";
+
+// NEP-297 events, logged as `EVENT_JSON:{"standard":..,"version":..,"event":..,"data":[..]}`.
+// Together they describe every change of sales, bids, bundles, swaps and offers, so indexers
+// can rebuild them. Bundles, swaps and offers are logged as they are stored
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SaleCreatedData {
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+    pub owner_id: AccountId,
+    pub approval_id: U64,
+    pub token_type: TokenType,
+    pub conditions: HashMap<Currency, U128>,
+    pub expires_at: Option<U64>,
+    pub reserved_for: Option<Vec<AccountId>>,
+    pub custodial: bool,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SaleUpdatedData {
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+    pub conditions: HashMap<Currency, U128>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SaleRemovedData {
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+    pub owner_id: AccountId,
+}
+
+/// bid placed, refunded to escrow or accepted by the seller
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BidData {
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+    pub bidder_id: AccountId,
+    pub ft_or_st: Currency,
+    pub price: U128,
+    pub expires_at: Option<U64>,
+}
+
+/// `payout` is what the price without the `fee` is paid out as, `None` if the purchase failed
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PurchaseData {
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+    pub seller_id: AccountId,
+    pub buyer_id: AccountId,
+    pub ft_or_st: Currency,
+    pub price: U128,
+    pub fee: U128,
+    pub payout: Option<Payout>,
+}
+
+/// `seller_id` filled offer `offer_id` with the token, followed by a purchase event
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct OfferAcceptedData {
+    pub offer_id: U64,
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+    pub seller_id: AccountId,
+}
+
+/// `feature` was paused or resumed by `account_id`
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PauseData {
+    pub feature: MarketFeature,
+    pub paused: bool,
+    pub account_id: AccountId,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+pub enum MarketEvent {
+    SaleCreated(Vec<SaleCreatedData>),
+    SaleUpdated(Vec<SaleUpdatedData>),
+    SaleRemoved(Vec<SaleRemovedData>),
+    BidPlaced(Vec<BidData>),
+    BidRefunded(Vec<BidData>),
+    BidAccepted(Vec<BidData>),
+    PurchaseCompleted(Vec<PurchaseData>),
+    PurchaseFailed(Vec<PurchaseData>),
+    BundleCreated(Vec<Bundle>),
+    BundleUpdated(Vec<Bundle>),
+    BundleRemoved(Vec<Bundle>),
+    SwapProposed(Vec<Swap>),
+    SwapUpdated(Vec<Swap>),
+    SwapRemoved(Vec<Swap>),
+    SwapCompleted(Vec<Swap>),
+    SwapFailed(Vec<Swap>),
+    CollectionOfferMade(Vec<CollectionOffer>),
+    CollectionOfferRemoved(Vec<CollectionOffer>),
+    CollectionOfferAccepted(Vec<OfferAcceptedData>),
+    OfferMade(Vec<TokenOffer>),
+    OfferRemoved(Vec<TokenOffer>),
+    OfferAccepted(Vec<OfferAcceptedData>),
+    PauseUpdated(Vec<PauseData>),
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct EventLog<'a> {
+    standard: &'static str,
+    version: &'static str,
+    #[serde(flatten)]
+    event: &'a MarketEvent,
+}
+
+impl MarketEvent {
+    pub fn emit(&self) {
+        let log = EventLog {
+            standard: EVENT_STANDARD,
+            version: EVENT_VERSION,
+            event: self,
+        };
+        env::log(
+            format!(
+                "EVENT_JSON:{}",
+                near_sdk::serde_json::to_string(&log).unwrap()
+            )
+            .as_bytes(),
+        );
+    }
+}
+
+impl BidData {
+    pub fn new(contract_and_token_id: &str, ft_or_st: &Currency, bid: &Bid) -> Self {
+        let (nft_contract_id, token_id) = split_contract_and_token_id(contract_and_token_id);
+        Self {
+            nft_contract_id,
+            token_id,
+            bidder_id: bid.owner_id.clone(),
+            ft_or_st: ft_or_st.clone(),
+            price: bid.price,
+            expires_at: bid.expires_at,
+        }
+    }
+}
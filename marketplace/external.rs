@@ -1,3 +1,6 @@
+// generated cross-contract calls mirror the full argument list of the callee
+#![allow(clippy::too_many_arguments)]
+
 use crate::*;
 
 /// external contract calls
@@ -8,11 +11,19 @@ trait ExtContract {
         &mut self,
         receiver_id: AccountId,
         token_id: TokenId,
-        approval_id: U64,
+        approval_id: Option<U64>,
         memo: Option<String>,
         balance: U128,
     );
+    fn nft_transfer(
+        &mut self,
+        receiver_id: AccountId,
+        token_id: TokenId,
+        approval_id: Option<U64>,
+        memo: Option<String>,
+    );
     fn nft_revoke(&mut self, token_id: TokenId, account_id: ValidAccountId);
+    fn nft_token(&self, token_id: TokenId);
     fn ft_transfer(
         &mut self,
         sender_id: Option<ValidAccountId>,
@@ -29,3 +40,66 @@ trait ExtContract {
         memo: Option<String>,
     );
 }
+
+/// self call
+
+#[ext_contract(ext_self)]
+trait ExtSelf {
+    fn resolve_purchase(
+        &mut self,
+        sold: SoldToken,
+        ft_or_st: Currency,
+        buyer_id: AccountId,
+        price: U128,
+        fee: U128,
+        refund_to_escrow: bool,
+    ) -> Promise;
+    fn resolve_withdraw(&mut self, owner_id: AccountId, ft_or_st: Currency, amount: U128) -> U128;
+    fn resolve_bundle_purchase(
+        &mut self,
+        bundle: Bundle,
+        ft_or_st: Currency,
+        buyer_id: AccountId,
+        price: U128,
+        fee: U128,
+        refund_to_escrow: bool,
+    ) -> U128;
+    fn resolve_delivery(
+        &mut self,
+        receiver_id: AccountId,
+        tokens: Vec<HeldToken>,
+    ) -> Vec<HeldToken>;
+    fn resolve_swap(&mut self, swap: Swap);
+    fn resolve_verify_sale(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        owner_id: AccountId,
+        approval_id: U64,
+    ) -> bool;
+    fn resolve_make_offer(
+        &mut self,
+        owner_id: AccountId,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        ft_or_st: Currency,
+        price: U128,
+    ) -> Option<U64>;
+    fn resolve_accept_offer(
+        &mut self,
+        offer_id: U64,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        owner_id: AccountId,
+        approval_id: U64,
+    ) -> Promise;
+    fn resolve_accept_collection_offer(
+        &mut self,
+        offer_id: U64,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        owner_id: AccountId,
+        approval_id: U64,
+    ) -> Promise;
+    fn resolve_treasury_withdraw(&mut self, ft_or_st: Currency, amount: U128) -> U128;
+}
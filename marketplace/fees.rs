@@ -0,0 +1,158 @@
+use crate::*;
+use near_sdk::PromiseResult;
+
+/// same cap as the NFT contract royalty
+const MAX_FEE_BPS: u32 = 1000;
+const GAS_FOR_RESOLVE_TREASURY_WITHDRAW: Gas = 10_000_000_000_000;
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FeesJson {
+    pub fee_bps: u32,
+    pub by_ft_or_st: HashMap<Currency, u32>,
+    pub by_nft_contract_id: HashMap<AccountId, u32>,
+}
+
+#[near_bindgen]
+impl Marketplace {
+    /// only owner, fee in basis points taken from every purchase
+    pub fn set_fee(&mut self, fee_bps: u32) {
+        self.assert_owner();
+        assert_fee_bps(fee_bps);
+        self.fee_bps = fee_bps;
+    }
+
+    /// only owner, overrides `fee_bps` for purchases in given currency, `None` removes the override
+    pub fn set_ft_or_st_fee(
+        &mut self,
+        ft_or_st_token_id: ValidAccountId,
+        st_symbol: Option<String>,
+        fee_bps: Option<u32>,
+    ) {
+        self.assert_owner();
+        let ft_or_st = Currency::new(ft_or_st_token_id.as_ref(), st_symbol);
+        if let Some(fee_bps) = fee_bps {
+            assert_fee_bps(fee_bps);
+            self.fee_bps_by_ft_or_st.insert(&ft_or_st, &fee_bps);
+        } else {
+            self.fee_bps_by_ft_or_st.remove(&ft_or_st);
+        }
+    }
+
+    /// only owner, overrides any other fee for tokens of given NFT contract, `None` removes the override
+    pub fn set_nft_contract_fee(&mut self, nft_contract_id: ValidAccountId, fee_bps: Option<u32>) {
+        self.assert_owner();
+        if let Some(fee_bps) = fee_bps {
+            assert_fee_bps(fee_bps);
+            self.fee_bps_by_nft_contract_id
+                .insert(nft_contract_id.as_ref(), &fee_bps);
+        } else {
+            self.fee_bps_by_nft_contract_id
+                .remove(nft_contract_id.as_ref());
+        }
+    }
+
+    /// only owner, transfers accrued fees of given currency to `receiver_id` (owner by default)
+    #[payable]
+    pub fn withdraw_treasury(
+        &mut self,
+        ft_or_st_token_id: ValidAccountId,
+        st_symbol: Option<String>,
+        receiver_id: Option<ValidAccountId>,
+    ) -> Promise {
+        assert_one_yocto();
+        self.assert_owner();
+        let ft_or_st = Currency::new(ft_or_st_token_id.as_ref(), st_symbol);
+        let amount = self.treasury.remove(&ft_or_st).unwrap_or(0);
+        assert!(amount > 0, "Nothing to withdraw");
+        let receiver_id = receiver_id
+            .map(|a| a.into())
+            .unwrap_or_else(|| self.owner_id.clone());
+
+        transfer_ft_or_st(&ft_or_st, receiver_id, U128(amount)).then(
+            ext_self::resolve_treasury_withdraw(
+                ft_or_st,
+                U128(amount),
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_RESOLVE_TREASURY_WITHDRAW,
+            ),
+        )
+    }
+
+    /// self callback, returns the amount that was actually withdrawn
+    #[private]
+    pub fn resolve_treasury_withdraw(&mut self, ft_or_st: Currency, amount: U128) -> U128 {
+        match env::promise_result(0) {
+            PromiseResult::Successful(_) => amount,
+            _ => {
+                self.internal_treasury_deposit(&ft_or_st, amount.0);
+                U128(0)
+            }
+        }
+    }
+
+    /// views
+    pub fn get_fees(&self) -> FeesJson {
+        FeesJson {
+            fee_bps: self.fee_bps,
+            by_ft_or_st: self.fee_bps_by_ft_or_st.iter().collect(),
+            by_nft_contract_id: self.fee_bps_by_nft_contract_id.iter().collect(),
+        }
+    }
+
+    /// fee applied to a purchase of a token of `nft_contract_id` in given currency
+    pub fn get_fee_bps(
+        &self,
+        nft_contract_id: ValidAccountId,
+        ft_or_st_token_id: ValidAccountId,
+        st_symbol: Option<String>,
+    ) -> u32 {
+        self.internal_fee_bps(
+            nft_contract_id.as_ref(),
+            &Currency::new(ft_or_st_token_id.as_ref(), st_symbol),
+        )
+    }
+
+    pub fn get_treasury_balances(&self) -> HashMap<Currency, U128> {
+        self.treasury
+            .iter()
+            .map(|(ft_or_st, balance)| (ft_or_st, U128(balance)))
+            .collect()
+    }
+}
+
+impl Marketplace {
+    /// NFT contract fee takes precedence over currency fee, which takes precedence over `fee_bps`
+    pub(crate) fn internal_fee_bps(&self, nft_contract_id: &AccountId, ft_or_st: &Currency) -> u32 {
+        self.fee_bps_by_nft_contract_id
+            .get(nft_contract_id)
+            .or_else(|| self.fee_bps_by_ft_or_st.get(ft_or_st))
+            .unwrap_or(self.fee_bps)
+    }
+
+    pub(crate) fn internal_fee(
+        &self,
+        nft_contract_id: &AccountId,
+        ft_or_st: &Currency,
+        price: Balance,
+    ) -> Balance {
+        price * u128::from(self.internal_fee_bps(nft_contract_id, ft_or_st)) / 10_000
+    }
+
+    pub(crate) fn internal_treasury_deposit(&mut self, ft_or_st: &Currency, amount: Balance) {
+        if amount == 0 {
+            return;
+        }
+        let balance = self.treasury.get(ft_or_st).unwrap_or(0);
+        self.treasury.insert(ft_or_st, &(balance + amount));
+    }
+}
+
+fn assert_fee_bps(fee_bps: u32) {
+    assert!(
+        fee_bps <= MAX_FEE_BPS,
+        "Fee can't be more than {} basis points",
+        MAX_FEE_BPS
+    );
+}
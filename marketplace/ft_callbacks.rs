@@ -1,8 +1,7 @@
 use crate::*;
 
 /// callbacks from FT Contracts
-
-trait FungibleTokenReceiver {
+pub trait FungibleTokenReceiver {
     fn ft_on_transfer(
         &mut self,
         sender_id: AccountId,
@@ -19,9 +18,56 @@ impl FungibleTokenReceiver for Marketplace {
         amount: U128,
         msg: String,
     ) -> PromiseOrValue<U128> {
+        self.internal_on_transfer(
+            Currency::Ft(env::predecessor_account_id()),
+            sender_id,
+            amount,
+            msg,
+        )
+    }
+}
+
+impl Marketplace {
+    /// FT or ST pushed to the marketplace by `ft_transfer_call` or `st_transfer_call`,
+    /// the unused amount is returned to the sender by the token contract
+    pub(crate) fn internal_on_transfer(
+        &mut self,
+        ft_or_st: Currency,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        // empty msg tops up the sender's escrow, e.g. to fund collection offers,
+        // the sender's storage deposit must cover the escrow balance
+        if msg.is_empty() {
+            assert!(
+                self.ft_token_ids.contains(&ft_or_st),
+                "Token {} not supported by this market",
+                ft_or_st
+            );
+            self.internal_escrow_deposit(&sender_id, &ft_or_st, amount.0);
+            self.internal_assert_storage_balance(&sender_id);
+            return PromiseOrValue::Value(U128(0));
+        }
+
+        if let Ok(BundlePurchaseArgs { bundle_id }) = near_sdk::serde_json::from_str(&msg) {
+            let price = *self
+                .bundles
+                .get(&bundle_id.0)
+                .expect("No bundle")
+                .conditions
+                .get(&ft_or_st)
+                .expect("Not for sale in that token type");
+            assert_eq!(amount, price, "Amount must be equal to the price");
+            return self
+                .process_bundle_purchase(bundle_id.0, ft_or_st, price, sender_id, false)
+                .into();
+        }
+
         let PurchaseArgs {
             nft_contract_id,
             token_id,
+            expires_at,
         } = near_sdk::serde_json::from_str(&msg).expect("Invalid PurchaseArgs");
 
         let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
@@ -29,34 +75,31 @@ impl FungibleTokenReceiver for Marketplace {
             .sales
             .get(&contract_and_token_id)
             .expect("No sale in ft_on_transfer");
-        assert_ne!(sale.owner_id, sender_id, "Cannot buy your own sale.");
-
-        let ft_token_id = env::predecessor_account_id();
-        let price = *sale
-            .conditions
-            .get(&ft_token_id)
-            .expect("Not for sale in that token type");
+        let price = sale.assert_buyable(&sender_id, &ft_or_st);
 
         assert!(amount.0 > 0, "Amount must be greater than 0");
 
-        if amount == price {
+        // a price of 0 only takes bids
+        if price.0 > 0 && amount == price {
             self.process_purchase(
                 nft_contract_id.into(),
                 token_id,
-                ft_token_id,
-                None,
+                ft_or_st,
                 price,
                 sender_id,
+                false,
             )
             .into()
         } else {
             self.add_bid(
                 contract_and_token_id,
                 price.0,
-                amount.0,
-                ft_token_id,
-                None,
-                sender_id,
+                ft_or_st,
+                Bid {
+                    owner_id: sender_id,
+                    price: amount,
+                    expires_at,
+                },
                 &mut sale,
             );
             PromiseOrValue::Value(U128(0))
@@ -0,0 +1,170 @@
+use crate::*;
+
+/// latest trades kept per token and per NFT contract
+const MAX_TOKEN_TRADES: usize = 10;
+const MAX_COLLECTION_TRADES: usize = 30;
+/// tokens with trades kept, the trades of the least recently traded token are dropped first
+pub(crate) const MAX_TRADED_TOKENS: u64 = 1000;
+
+/// token changing hands in a purchase, passed to `resolve_purchase` to record the trade
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SoldToken {
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+    pub token_type: TokenType,
+    pub seller_id: AccountId,
+    /// held by the marketplace, its payout share goes to the seller
+    pub custodial: bool,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Trade {
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+    pub token_type: TokenType,
+    pub seller_id: AccountId,
+    pub buyer_id: AccountId,
+    pub ft_or_st: Currency,
+    pub price: U128,
+    pub timestamp: U64,
+}
+
+/// aggregates of every trade of an NFT contract, or of one token type of it
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+pub struct TradeStats {
+    pub trades: u64,
+    pub volume: HashMap<Currency, U128>,
+    pub last_sale: Option<Trade>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TradeStatsJson {
+    pub trades: U64,
+    pub volume: HashMap<Currency, U128>,
+    pub last_sale: Option<Trade>,
+    /// cheapest open sale in the requested currency, see `internal_floor_price`
+    pub floor_price: Option<U128>,
+}
+
+#[near_bindgen]
+impl Marketplace {
+    /// views, latest trades of a token, newest first. Empty once `MAX_TRADED_TOKENS` other
+    /// tokens were traded since
+    pub fn get_token_trades(&self, nft_contract_id: AccountId, token_id: TokenId) -> Vec<Trade> {
+        self.trades_by_token
+            .get(&format!("{}{}{}", nft_contract_id, DELIMETER, token_id))
+            .unwrap_or_default()
+    }
+
+    /// latest trades of an NFT contract, newest first
+    pub fn get_collection_trades(
+        &self,
+        nft_contract_id: AccountId,
+        from_index: U64,
+        limit: U64,
+    ) -> Vec<Trade> {
+        self.trades_by_nft_contract_id
+            .get(&nft_contract_id)
+            .unwrap_or_default()
+            .into_iter()
+            .skip(from_index.0 as usize)
+            .take(limit.0 as usize)
+            .collect()
+    }
+
+    /// stats of an NFT contract, or of one of its token types. Floor price needs a currency
+    pub fn get_collection_stats(
+        &self,
+        nft_contract_id: AccountId,
+        token_type: TokenType,
+        ft_or_st_token_id: Option<ValidAccountId>,
+        st_symbol: Option<String>,
+    ) -> TradeStatsJson {
+        let TradeStats {
+            trades,
+            volume,
+            last_sale,
+        } = self
+            .trade_stats
+            .get(&stats_key(&nft_contract_id, &token_type))
+            .unwrap_or_default();
+        let floor_price = ft_or_st_token_id.and_then(|ft_or_st_token_id| {
+            let ft_or_st = Currency::new(ft_or_st_token_id.as_ref(), st_symbol);
+            let filter = SaleFilter {
+                nft_contract_id: Some(nft_contract_id),
+                token_type,
+                ..Default::default()
+            };
+            self.internal_floor_price(&filter, &ft_or_st)
+        });
+        TradeStatsJson {
+            trades: U64(trades),
+            volume,
+            last_sale,
+            floor_price,
+        }
+    }
+}
+
+impl Marketplace {
+    pub(crate) fn internal_record_trade(&mut self, trade: Trade) {
+        let contract_and_token_id =
+            format!("{}{}{}", trade.nft_contract_id, DELIMETER, trade.token_id);
+        let mut token_trades = self
+            .trades_by_token
+            .get(&contract_and_token_id)
+            .unwrap_or_default();
+        if let Some(last_trade) = token_trades.first() {
+            self.traded_tokens
+                .remove(&(last_trade.timestamp.0, contract_and_token_id.clone()));
+        }
+        token_trades.insert(0, trade.clone());
+        token_trades.truncate(MAX_TOKEN_TRADES);
+        self.trades_by_token
+            .insert(&contract_and_token_id, &token_trades);
+        self.traded_tokens
+            .insert(&(trade.timestamp.0, contract_and_token_id), &());
+        if self.traded_tokens.len() > MAX_TRADED_TOKENS {
+            let oldest = self.traded_tokens.min().unwrap();
+            self.traded_tokens.remove(&oldest);
+            self.trades_by_token.remove(&oldest.1);
+        }
+
+        let mut collection_trades = self
+            .trades_by_nft_contract_id
+            .get(&trade.nft_contract_id)
+            .unwrap_or_default();
+        collection_trades.insert(0, trade.clone());
+        collection_trades.truncate(MAX_COLLECTION_TRADES);
+        self.trades_by_nft_contract_id
+            .insert(&trade.nft_contract_id, &collection_trades);
+
+        let mut keys = vec![stats_key(&trade.nft_contract_id, &None)];
+        if trade.token_type.is_some() {
+            keys.push(stats_key(&trade.nft_contract_id, &trade.token_type));
+        }
+        for key in keys {
+            let mut stats = self.trade_stats.get(&key).unwrap_or_default();
+            // recorded in purchase callbacks after the token moved, so never panic here
+            stats.trades = stats.trades.saturating_add(1);
+            let volume = stats
+                .volume
+                .entry(trade.ft_or_st.clone())
+                .or_insert(U128(0));
+            volume.0 = volume.0.saturating_add(trade.price.0);
+            stats.last_sale = Some(trade.clone());
+            self.trade_stats.insert(&key, &stats);
+        }
+    }
+}
+
+/// `nft_contract_id`, or `nft_contract_id||token_type` for stats of a token type
+fn stats_key(nft_contract_id: &str, token_type: &TokenType) -> String {
+    match token_type {
+        Some(token_type) => format!("{}{}{}", nft_contract_id, DELIMETER, token_type),
+        None => nft_contract_id.to_string(),
+    }
+}
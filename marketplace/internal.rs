@@ -6,6 +6,78 @@ pub(crate) fn hash_account_id(account_id: &AccountId) -> CryptoHash {
     hash
 }
 
+/// adds `id` to the set of `account_id`, creating the set under `inner_key` if missing
+pub(crate) fn add_id_by_account_id<T: BorshSerialize + BorshDeserialize>(
+    ids_by_account_id: &mut LookupMap<AccountId, UnorderedSet<T>>,
+    account_id: &AccountId,
+    id: T,
+    inner_key: impl FnOnce(CryptoHash) -> StorageKey,
+) {
+    let mut ids = ids_by_account_id.get(account_id).unwrap_or_else(|| {
+        UnorderedSet::new(inner_key(hash_account_id(account_id)).try_to_vec().unwrap())
+    });
+    ids.insert(&id);
+    ids_by_account_id.insert(account_id, &ids);
+}
+
+/// removes `id` from the set of `account_id`, dropping the set once empty
+pub(crate) fn remove_id_by_account_id<T: BorshSerialize + BorshDeserialize>(
+    ids_by_account_id: &mut LookupMap<AccountId, UnorderedSet<T>>,
+    account_id: &AccountId,
+    id: T,
+) {
+    let mut ids = ids_by_account_id
+        .get(account_id)
+        .expect("No ids by account_id");
+    ids.remove(&id);
+    if ids.is_empty() {
+        ids_by_account_id.remove(account_id);
+    } else {
+        ids_by_account_id.insert(account_id, &ids);
+    }
+}
+
+/// splits `<nft contract id>||<token id>` into its parts
+pub(crate) fn split_contract_and_token_id(contract_and_token_id: &str) -> (AccountId, TokenId) {
+    let mut parts = contract_and_token_id.splitn(2, DELIMETER);
+    let nft_contract_id = parts.next().unwrap().to_string();
+    let token_id = parts
+        .next()
+        .expect("Invalid contract and token id")
+        .to_string();
+    (nft_contract_id, token_id)
+}
+
+/// sends `amount` of `currency` from the marketplace to `receiver_id`
+pub(crate) fn transfer_ft_or_st(
+    currency: &Currency,
+    receiver_id: AccountId,
+    amount: U128,
+) -> Promise {
+    match currency {
+        Currency::Near => Promise::new(receiver_id).transfer(amount.0),
+        Currency::Ft(ft_token_id) => nft_sale::ft_transfer(
+            None,
+            receiver_id,
+            amount,
+            None,
+            ft_token_id,
+            1,
+            GAS_FOR_FT_TRANSFER,
+        ),
+        Currency::St(st_token_id, st_symbol) => nft_sale::st_transfer(
+            st_symbol.clone(),
+            None,
+            receiver_id,
+            amount,
+            None,
+            st_token_id,
+            1,
+            GAS_FOR_FT_TRANSFER,
+        ),
+    }
+}
+
 impl Marketplace {
     pub(crate) fn assert_owner(&self) {
         assert_eq!(
@@ -22,6 +94,10 @@ impl Marketplace {
     ) -> Sale {
         let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
         let sale = self.sales.remove(&contract_and_token_id).expect("No sale");
+        self.internal_release_storage(
+            &sale.owner_id,
+            &ListingId::Sale(contract_and_token_id.clone()),
+        );
 
         let mut by_owner_id = self
             .by_owner_id
@@ -46,21 +122,39 @@ impl Marketplace {
                 .insert(&nft_contract_id, &by_nft_contract_id);
         }
 
-        let token_type = sale.token_type.clone();
-        if let Some(token_type) = token_type {
+        if let Some(expires_at) = sale.expires_at {
+            self.sales_by_expiry
+                .remove(&(expires_at.0, contract_and_token_id.clone()));
+        }
+
+        self.sales_by_listed_at
+            .remove(&(sale.listed_at.0, contract_and_token_id.clone()));
+        for (ft_or_st, price) in sale.conditions.iter() {
+            self.sales_by_price
+                .remove(&(ft_or_st.clone(), price.0, contract_and_token_id.clone()));
+        }
+
+        if let Some(token_type) = sale.token_type.as_ref() {
+            let contract_and_token_type = format!("{}{}{}", nft_contract_id, DELIMETER, token_type);
             let mut by_nft_token_type = self
                 .by_nft_token_type
-                .get(&token_type)
+                .get(&contract_and_token_type)
                 .expect("No sale by nft_token_type");
-            by_nft_token_type.remove(&token_type);
+            by_nft_token_type.remove(&contract_and_token_id);
             if by_nft_token_type.is_empty() {
-                self.by_nft_token_type.remove(&token_type);
+                self.by_nft_token_type.remove(&contract_and_token_type);
             } else {
                 self.by_nft_token_type
-                    .insert(&token_type, &by_nft_token_type);
+                    .insert(&contract_and_token_type, &by_nft_token_type);
             }
         }
 
+        MarketEvent::SaleRemoved(vec![SaleRemovedData {
+            nft_contract_id,
+            token_id,
+            owner_id: sale.owner_id.clone(),
+        }])
+        .emit();
         sale
     }
 }
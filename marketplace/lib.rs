@@ -1,14 +1,35 @@
+mod bundle;
+mod collection_offers;
+mod currency;
+mod delivery;
+mod escrow;
+mod events;
 mod external;
+mod fees;
 mod ft_callbacks;
+mod history;
 mod internal;
+mod migrate;
 mod nft_callbacks;
+mod nft_contracts;
+mod pause;
+mod quote;
 mod sale;
 mod sale_views;
+mod settlement;
+mod st_callbacks;
+mod storage;
+mod swap;
+mod token_offers;
+mod verify;
+
+#[cfg(test)]
+mod test;
 
 near_sdk::setup_alloc!();
 
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::{LookupMap, UnorderedMap, UnorderedSet};
+use near_sdk::collections::{LookupMap, TreeMap, UnorderedMap, UnorderedSet};
 use near_sdk::json_types::{ValidAccountId, U128, U64};
 use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::{
@@ -19,26 +40,56 @@ use std::cmp::min;
 use std::collections::HashMap;
 use std::convert::TryFrom;
 
-use crate::external::*;
+pub use crate::bundle::*;
+pub use crate::collection_offers::*;
+pub use crate::currency::*;
+pub use crate::delivery::*;
+pub use crate::events::*;
+pub use crate::external::*;
+pub use crate::fees::*;
+pub use crate::ft_callbacks::*;
+pub use crate::history::*;
 use crate::internal::*;
-use crate::sale::*;
+pub use crate::nft_callbacks::*;
+pub use crate::nft_contracts::*;
+pub use crate::pause::*;
+pub use crate::quote::*;
+pub use crate::sale::*;
+pub use crate::sale_views::*;
+pub use crate::settlement::*;
+pub use crate::st_callbacks::*;
+pub use crate::storage::*;
+pub use crate::swap::*;
+pub use crate::token_offers::*;
+pub use crate::verify::*;
 use near_sdk::env::STORAGE_PRICE_PER_BYTE;
 
 // TODO check seller supports storage_deposit at ft_token_id they want to post sale in
 
 const NO_DEPOSIT: Balance = 0;
-const STORAGE_PER_SALE: u128 = 1000 * STORAGE_PRICE_PER_BYTE;
+/// measuring how many royalties can be paid
+const GAS_FOR_FT_TRANSFER: Gas = 5_000_000_000_000;
+const GAS_FOR_NFT_TRANSFER: Gas = 15_000_000_000_000;
+/// transfer and payout of a token filling a collection or token offer, once its type is known
+const GAS_FOR_RESOLVE_ACCEPT_OFFER: Gas = 150_000_000_000_000;
+/// estimate for UIs, the storage of listings is measured
+const STORAGE_PER_SALE: u128 = 2000 * STORAGE_PRICE_PER_BYTE;
 static DELIMETER: &str = "||";
-static MARKETPLACE_ACCOUNT_ID: &str = "marketplace.momentize.testnet";
-static FT_ACCOUNT_ID: &str = "ft.momentize.testnet";
+static NEAR: &str = "near";
 
 pub type TokenId = String;
 pub type TokenType = Option<String>;
-pub type FTOrSTIdAndStSymbol = String;
 pub type ContractAndTokenId = String;
 // TODO: Capital U128
 pub type Payout = HashMap<AccountId, U128>;
 
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StTokenId {
+    pub st_token_id: ValidAccountId,
+    pub st_symbol: String,
+}
+
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
 pub struct Marketplace {
@@ -46,9 +97,56 @@ pub struct Marketplace {
     pub sales: UnorderedMap<ContractAndTokenId, Sale>,
     pub by_owner_id: LookupMap<AccountId, UnorderedSet<ContractAndTokenId>>,
     pub by_nft_contract_id: LookupMap<AccountId, UnorderedSet<TokenId>>,
-    pub by_nft_token_type: LookupMap<AccountId, UnorderedSet<TokenId>>,
-    pub ft_token_ids: UnorderedSet<AccountId>, // in case of st
+    /// keyed by `nft_contract_id||token_type`
+    pub by_nft_token_type: LookupMap<String, UnorderedSet<ContractAndTokenId>>,
+    pub ft_token_ids: UnorderedSet<Currency>,
     pub storage_deposits: LookupMap<AccountId, Balance>,
+    pub escrow: LookupMap<AccountId, UnorderedMap<Currency, Balance>>,
+    pub collection_offers: UnorderedMap<u64, CollectionOffer>,
+    pub collection_offers_by_nft_contract_id: LookupMap<AccountId, UnorderedSet<u64>>,
+    pub next_collection_offer_id: u64,
+    pub fee_bps: u32,
+    pub fee_bps_by_ft_or_st: UnorderedMap<Currency, u32>,
+    pub fee_bps_by_nft_contract_id: UnorderedMap<AccountId, u32>,
+    pub treasury: UnorderedMap<Currency, Balance>,
+    /// sales with `expires_at`, ordered by it
+    pub sales_by_expiry: TreeMap<(u64, ContractAndTokenId), ()>,
+    /// every price of every sale, ordered by currency then price
+    pub sales_by_price: TreeMap<(Currency, Balance, ContractAndTokenId), ()>,
+    pub sales_by_listed_at: TreeMap<(u64, ContractAndTokenId), ()>,
+    pub bundles: UnorderedMap<u64, Bundle>,
+    pub bundles_by_owner_id: LookupMap<AccountId, UnorderedSet<u64>>,
+    pub next_bundle_id: u64,
+    pub swaps: UnorderedMap<u64, Swap>,
+    pub swaps_by_proposer_id: LookupMap<AccountId, UnorderedSet<u64>>,
+    pub swaps_by_counterparty_id: LookupMap<AccountId, UnorderedSet<u64>>,
+    pub next_swap_id: u64,
+    pub storage_usage_by_owner_id: LookupMap<AccountId, u64>,
+    pub storage_usage_by_listing_id: LookupMap<ListingId, u64>,
+    pub deprecated_ft_token_ids: UnorderedSet<Currency>,
+    pub trades_by_token: LookupMap<ContractAndTokenId, Vec<Trade>>,
+    /// tokens in `trades_by_token`, ordered by their last trade
+    pub traded_tokens: TreeMap<(u64, ContractAndTokenId), ()>,
+    pub trades_by_nft_contract_id: LookupMap<AccountId, Vec<Trade>>,
+    /// keyed by `nft_contract_id` and `nft_contract_id||token_type`
+    pub trade_stats: LookupMap<String, TradeStats>,
+    /// allowlisted NFT contracts
+    pub nft_contracts: UnorderedMap<AccountId, NFTContractPolicy>,
+    /// purchases with payouts still to be transferred
+    pub settlements: UnorderedMap<u64, Settlement>,
+    pub next_settlement_id: u64,
+    pub token_offers: UnorderedMap<u64, TokenOffer>,
+    /// keyed by the owner of the token when the offer was made
+    pub token_offers_by_owner_id: LookupMap<AccountId, UnorderedSet<u64>>,
+    pub token_offers_by_bidder_id: LookupMap<AccountId, UnorderedSet<u64>>,
+    pub next_token_offer_id: u64,
+    pub paused: PauseStatus,
+    /// accounts that can pause the marketplace besides the owner
+    pub guardians: UnorderedSet<AccountId>,
+    /// tokens in custody whose transfer to their receiver failed, see `claim_tokens`
+    pub undelivered_tokens: LookupMap<AccountId, Vec<HeldToken>>,
+    /// sales still in the layout before `migrate`, see `migrate_sales`
+    pub sales_to_migrate: u64,
 }
 
 /// Helper structure to for keys of the persistent collections.
@@ -63,12 +161,51 @@ pub enum StorageKey {
     ByNFTTokenTypeInner { token_type_hash: CryptoHash },
     FTTokenIds,
     StorageDeposits,
+    Escrow,
+    EscrowInner { account_id_hash: CryptoHash },
+    CollectionOffers,
+    CollectionOffersByNFTContractId,
+    CollectionOffersByNFTContractIdInner { account_id_hash: CryptoHash },
+    FeeBpsByFTOrST,
+    FeeBpsByNFTContractId,
+    Treasury,
+    SalesByExpiry,
+    SalesByPrice,
+    SalesByListedAt,
+    Bundles,
+    BundlesByOwnerId,
+    BundlesByOwnerIdInner { account_id_hash: CryptoHash },
+    Swaps,
+    SwapsByProposerId,
+    SwapsByProposerIdInner { account_id_hash: CryptoHash },
+    SwapsByCounterpartyId,
+    SwapsByCounterpartyIdInner { account_id_hash: CryptoHash },
+    StorageUsageByOwnerId,
+    StorageUsageByListingId,
+    DeprecatedFTTokenIds,
+    TradesByToken,
+    TradesByNFTContractId,
+    TradeStats,
+    NFTContracts,
+    Settlements,
+    TokenOffers,
+    TokenOffersByOwnerId,
+    TokenOffersByOwnerIdInner { account_id_hash: CryptoHash },
+    TokenOffersByBidderId,
+    TokenOffersByBidderIdInner { account_id_hash: CryptoHash },
+    Guardians,
+    UndeliveredTokens,
+    TradedTokens,
 }
 
 #[near_bindgen]
 impl Marketplace {
     #[init]
-    pub fn new(owner_id: ValidAccountId, ft_token_ids: Option<Vec<ValidAccountId>>) -> Self {
+    pub fn new(
+        owner_id: ValidAccountId,
+        ft_token_ids: Option<Vec<ValidAccountId>>,
+        st_token_ids: Option<Vec<StTokenId>>,
+    ) -> Self {
         let mut this = Self {
             owner_id: owner_id.into(),
             sales: UnorderedMap::new(StorageKey::Sales.try_to_vec().unwrap()),
@@ -77,12 +214,85 @@ impl Marketplace {
             by_nft_token_type: LookupMap::new(StorageKey::ByNFTTokenType.try_to_vec().unwrap()),
             ft_token_ids: UnorderedSet::new(StorageKey::FTTokenIds.try_to_vec().unwrap()),
             storage_deposits: LookupMap::new(StorageKey::StorageDeposits.try_to_vec().unwrap()),
+            escrow: LookupMap::new(StorageKey::Escrow.try_to_vec().unwrap()),
+            collection_offers: UnorderedMap::new(
+                StorageKey::CollectionOffers.try_to_vec().unwrap(),
+            ),
+            collection_offers_by_nft_contract_id: LookupMap::new(
+                StorageKey::CollectionOffersByNFTContractId
+                    .try_to_vec()
+                    .unwrap(),
+            ),
+            next_collection_offer_id: 0,
+            fee_bps: 0,
+            fee_bps_by_ft_or_st: UnorderedMap::new(
+                StorageKey::FeeBpsByFTOrST.try_to_vec().unwrap(),
+            ),
+            fee_bps_by_nft_contract_id: UnorderedMap::new(
+                StorageKey::FeeBpsByNFTContractId.try_to_vec().unwrap(),
+            ),
+            treasury: UnorderedMap::new(StorageKey::Treasury.try_to_vec().unwrap()),
+            sales_by_expiry: TreeMap::new(StorageKey::SalesByExpiry.try_to_vec().unwrap()),
+            sales_by_price: TreeMap::new(StorageKey::SalesByPrice.try_to_vec().unwrap()),
+            sales_by_listed_at: TreeMap::new(StorageKey::SalesByListedAt.try_to_vec().unwrap()),
+            bundles: UnorderedMap::new(StorageKey::Bundles.try_to_vec().unwrap()),
+            bundles_by_owner_id: LookupMap::new(StorageKey::BundlesByOwnerId.try_to_vec().unwrap()),
+            next_bundle_id: 0,
+            swaps: UnorderedMap::new(StorageKey::Swaps.try_to_vec().unwrap()),
+            swaps_by_proposer_id: LookupMap::new(
+                StorageKey::SwapsByProposerId.try_to_vec().unwrap(),
+            ),
+            swaps_by_counterparty_id: LookupMap::new(
+                StorageKey::SwapsByCounterpartyId.try_to_vec().unwrap(),
+            ),
+            next_swap_id: 0,
+            storage_usage_by_owner_id: LookupMap::new(
+                StorageKey::StorageUsageByOwnerId.try_to_vec().unwrap(),
+            ),
+            storage_usage_by_listing_id: LookupMap::new(
+                StorageKey::StorageUsageByListingId.try_to_vec().unwrap(),
+            ),
+            deprecated_ft_token_ids: UnorderedSet::new(
+                StorageKey::DeprecatedFTTokenIds.try_to_vec().unwrap(),
+            ),
+            trades_by_token: LookupMap::new(StorageKey::TradesByToken.try_to_vec().unwrap()),
+            traded_tokens: TreeMap::new(StorageKey::TradedTokens.try_to_vec().unwrap()),
+            trades_by_nft_contract_id: LookupMap::new(
+                StorageKey::TradesByNFTContractId.try_to_vec().unwrap(),
+            ),
+            trade_stats: LookupMap::new(StorageKey::TradeStats.try_to_vec().unwrap()),
+            nft_contracts: UnorderedMap::new(StorageKey::NFTContracts.try_to_vec().unwrap()),
+            settlements: UnorderedMap::new(StorageKey::Settlements.try_to_vec().unwrap()),
+            next_settlement_id: 0,
+            token_offers: UnorderedMap::new(StorageKey::TokenOffers.try_to_vec().unwrap()),
+            token_offers_by_owner_id: LookupMap::new(
+                StorageKey::TokenOffersByOwnerId.try_to_vec().unwrap(),
+            ),
+            token_offers_by_bidder_id: LookupMap::new(
+                StorageKey::TokenOffersByBidderId.try_to_vec().unwrap(),
+            ),
+            next_token_offer_id: 0,
+            paused: PauseStatus::default(),
+            guardians: UnorderedSet::new(StorageKey::Guardians.try_to_vec().unwrap()),
+            undelivered_tokens: LookupMap::new(StorageKey::UndeliveredTokens.try_to_vec().unwrap()),
+            sales_to_migrate: 0,
         };
         // support NEAR by default
-        this.ft_token_ids.insert(&"near".to_string());
+        this.ft_token_ids.insert(&Currency::Near);
         if let Some(ft_token_ids) = ft_token_ids {
             for ft_token_id in ft_token_ids {
-                this.ft_token_ids.insert(ft_token_id.as_ref());
+                this.ft_token_ids
+                    .insert(&Currency::new(ft_token_id.as_ref(), None));
+            }
+        }
+        if let Some(st_token_ids) = st_token_ids {
+            for StTokenId {
+                st_token_id,
+                st_symbol,
+            } in st_token_ids
+            {
+                this.ft_token_ids
+                    .insert(&Currency::St(st_token_id.into(), st_symbol));
             }
         }
 
@@ -92,66 +302,48 @@ impl Marketplace {
     /// only owner
     pub fn add_ft_or_st_token_ids(
         &mut self,
-        mut ft_or_st_token_id: ValidAccountId,
+        ft_or_st_token_id: ValidAccountId,
         st_symbol: Option<String>,
     ) -> bool {
         self.assert_owner();
-        if let Some(st_symbol) = st_symbol {
-            ft_or_st_token_id = ValidAccountId::try_from(format!(
-                "{}{}{}",
-                ft_or_st_token_id, DELIMETER, st_symbol
-            ))
-            .unwrap();
-        }
-        self.ft_token_ids.insert(ft_or_st_token_id.as_ref())
+        let ft_or_st = Currency::new(ft_or_st_token_id.as_ref(), st_symbol);
+        self.deprecated_ft_token_ids.remove(&ft_or_st);
+        self.ft_token_ids.insert(&ft_or_st)
     }
 
-    /// TODO remove token (should check if sales can complete even if owner stops supporting token type)
-
-    #[payable]
-    pub fn storage_deposit(&mut self, account_id: Option<ValidAccountId>) {
-        let storage_account_id = account_id
-            .map(|a| a.into())
-            .unwrap_or_else(env::predecessor_account_id);
-        let deposit = env::attached_deposit();
-        assert!(
-            deposit >= STORAGE_PER_SALE,
-            "Requires minimum deposit of {}",
-            STORAGE_PER_SALE
-        );
-        let mut balance: u128 = self.storage_deposits.get(&storage_account_id).unwrap_or(0);
-        balance += deposit;
-        self.storage_deposits.insert(&storage_account_id, &balance);
+    /// only owner, deprecates the currency: no new listings or bids can use it, while existing
+    /// sales in it can still be bought, repriced or removed and its bids refunded
+    pub fn remove_ft_or_st_token_id(
+        &mut self,
+        ft_or_st_token_id: ValidAccountId,
+        st_symbol: Option<String>,
+    ) -> bool {
+        self.assert_owner();
+        let ft_or_st = Currency::new(ft_or_st_token_id.as_ref(), st_symbol);
+        assert_ne!(ft_or_st, Currency::Near, "Cannot remove near");
+        if !self.ft_token_ids.remove(&ft_or_st) {
+            return false;
+        }
+        self.deprecated_ft_token_ids.insert(&ft_or_st);
+        true
     }
 
-    #[payable]
-    pub fn storage_withdraw(&mut self) {
-        assert_one_yocto();
-        let owner_id = env::predecessor_account_id();
-        let mut amount = self.storage_deposits.remove(&owner_id).unwrap_or(0);
-        let sales = self.by_owner_id.get(&owner_id);
-        let len = if sales.is_some() {
-            sales.unwrap().len()
-        } else {
-            0
-        };
-        amount -= u128::from(len) * STORAGE_PER_SALE;
-        if amount > 0 {
-            Promise::new(owner_id).transfer(amount);
-        }
+    /// only owner
+    pub fn set_owner_id(&mut self, owner_id: ValidAccountId) {
+        self.assert_owner();
+        self.owner_id = owner_id.into();
     }
 
     /// views
-
-    pub fn supported_ft_token_ids(&self) -> Vec<AccountId> {
+    pub fn supported_ft_token_ids(&self) -> Vec<Currency> {
         self.ft_token_ids.to_vec()
     }
 
-    pub fn storage_amount(&self) -> U128 {
-        U128(STORAGE_PER_SALE)
+    pub fn deprecated_ft_token_ids(&self) -> Vec<Currency> {
+        self.deprecated_ft_token_ids.to_vec()
     }
 
-    pub fn storage_paid(&self, account_id: ValidAccountId) -> U128 {
-        U128(self.storage_deposits.get(account_id.as_ref()).unwrap_or(0))
+    pub fn get_owner_id(&self) -> AccountId {
+        self.owner_id.clone()
     }
 }
@@ -0,0 +1,174 @@
+use crate::*;
+
+/// what sellers deposited per sale before storage was measured, recorded for migrated sales
+const BASELINE_STORAGE_PER_SALE: u64 = 1000;
+
+// layout of the first deployed marketplace. Currencies were keyed by `ft_or_st_key` strings,
+// sales had one bid per currency and `by_nft_token_type` was keyed by the token type alone
+
+#[derive(BorshDeserialize)]
+struct OldMarketplace {
+    owner_id: AccountId,
+    /// values are still `OldSale`, rewritten by `migrate_sales`
+    sales: UnorderedMap<ContractAndTokenId, Sale>,
+    by_owner_id: LookupMap<AccountId, UnorderedSet<ContractAndTokenId>>,
+    by_nft_contract_id: LookupMap<AccountId, UnorderedSet<TokenId>>,
+    by_nft_token_type: LookupMap<String, UnorderedSet<ContractAndTokenId>>,
+    ft_token_ids: UnorderedSet<String>,
+    storage_deposits: LookupMap<AccountId, Balance>,
+}
+
+#[derive(BorshDeserialize)]
+struct OldBid {
+    owner_id: AccountId,
+    price: U128,
+}
+
+#[derive(BorshDeserialize)]
+struct OldSale {
+    owner_id: AccountId,
+    approval_id: U64,
+    token_type: Option<String>,
+    conditions: HashMap<String, U128>,
+    bids: HashMap<String, OldBid>,
+}
+
+#[near_bindgen]
+impl Marketplace {
+    /// upgrades the state of the first deployed marketplace. Call it once, in the transaction
+    /// deploying the code, e.g. `near deploy --initFunction migrate --initArgs '{}'`.
+    /// The marketplace is paused until the owner has rewritten every sale with `migrate_sales`
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let old: OldMarketplace = env::state_read().expect("Not initialized");
+        // the set keeps its storage prefix, currencies are written back typed
+        let ft_token_ids = old.ft_token_ids.to_vec();
+        let mut old_ft_token_ids = old.ft_token_ids;
+        old_ft_token_ids.clear();
+
+        let mut this = Self::new(ValidAccountId::try_from(old.owner_id).unwrap(), None, None);
+        for ft_or_st in ft_token_ids {
+            this.ft_token_ids.insert(&Currency::from_key(&ft_or_st));
+        }
+        this.sales_to_migrate = old.sales.len();
+        this.sales = old.sales;
+        this.by_owner_id = old.by_owner_id;
+        this.by_nft_contract_id = old.by_nft_contract_id;
+        this.by_nft_token_type = old.by_nft_token_type;
+        this.storage_deposits = old.storage_deposits;
+        this.paused = PauseStatus {
+            listing: true,
+            bidding: true,
+            purchasing: true,
+        };
+        this
+    }
+
+    /// only owner, rewrites up to `limit` sales in the current layout, last ones first so
+    /// sales listed or removed in the meantime don't move unmigrated ones.
+    /// Returns how many sales are left
+    pub fn migrate_sales(&mut self, limit: u64) -> U64 {
+        self.assert_owner();
+        let end = self.sales_to_migrate;
+        let start = end.saturating_sub(limit);
+        for index in (start..end).rev() {
+            let key = self.sales.keys_as_vector().get_raw(index).unwrap();
+            let value = self.sales.values_as_vector().get_raw(index).unwrap();
+            let contract_and_token_id =
+                ContractAndTokenId::try_from_slice(&key).expect("Cannot migrate sale");
+            let sale = OldSale::try_from_slice(&value).expect("Cannot migrate sale");
+            let sale = self.internal_migrate_sale(contract_and_token_id, sale);
+            self.sales.insert_raw(&key, &sale.try_to_vec().unwrap());
+        }
+        self.sales_to_migrate = start;
+        U64(start)
+    }
+}
+
+impl Marketplace {
+    /// converts the sale and fills the indexes it wasn't in yet
+    fn internal_migrate_sale(
+        &mut self,
+        contract_and_token_id: ContractAndTokenId,
+        sale: OldSale,
+    ) -> Sale {
+        let mut conditions = HashMap::new();
+        for (ft_or_st, price) in sale.conditions {
+            let ft_or_st = Currency::from_key(&ft_or_st);
+            self.sales_by_price.insert(
+                &(ft_or_st.clone(), price.0, contract_and_token_id.clone()),
+                &(),
+            );
+            conditions.insert(ft_or_st, price);
+        }
+        let bids = sale
+            .bids
+            .into_iter()
+            .map(|(ft_or_st, bid)| {
+                (
+                    Currency::from_key(&ft_or_st),
+                    vec![Bid {
+                        owner_id: bid.owner_id,
+                        price: bid.price,
+                        expires_at: None,
+                    }],
+                )
+            })
+            .collect();
+        let listed_at = env::block_timestamp();
+        self.sales_by_listed_at
+            .insert(&(listed_at, contract_and_token_id.clone()), &());
+
+        if let Some(token_type) = sale.token_type.as_ref() {
+            if let Some(mut by_token_type) = self.by_nft_token_type.get(token_type) {
+                by_token_type.remove(&contract_and_token_id);
+                if by_token_type.is_empty() {
+                    self.by_nft_token_type.remove(token_type);
+                } else {
+                    self.by_nft_token_type.insert(token_type, &by_token_type);
+                }
+            }
+            let (nft_contract_id, _) = split_contract_and_token_id(&contract_and_token_id);
+            let contract_and_token_type = format!("{}{}{}", nft_contract_id, DELIMETER, token_type);
+            let mut by_nft_token_type = self
+                .by_nft_token_type
+                .get(&contract_and_token_type)
+                .unwrap_or_else(|| {
+                    UnorderedSet::new(
+                        StorageKey::ByNFTTokenTypeInner {
+                            token_type_hash: hash_account_id(&contract_and_token_type),
+                        }
+                        .try_to_vec()
+                        .unwrap(),
+                    )
+                });
+            by_nft_token_type.insert(&contract_and_token_id);
+            self.by_nft_token_type
+                .insert(&contract_and_token_type, &by_nft_token_type);
+        }
+
+        let used = self
+            .storage_usage_by_owner_id
+            .get(&sale.owner_id)
+            .unwrap_or(0);
+        self.storage_usage_by_owner_id
+            .insert(&sale.owner_id, &(used + BASELINE_STORAGE_PER_SALE));
+        self.storage_usage_by_listing_id.insert(
+            &ListingId::Sale(contract_and_token_id),
+            &BASELINE_STORAGE_PER_SALE,
+        );
+
+        Sale {
+            owner_id: sale.owner_id,
+            approval_id: sale.approval_id,
+            token_type: sale.token_type,
+            conditions,
+            bids,
+            expires_at: None,
+            reserved_for: None,
+            listed_at: U64(listed_at),
+            custodial: false,
+        }
+    }
+}
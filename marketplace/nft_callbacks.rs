@@ -1,5 +1,8 @@
 use crate::*;
 
+/// buyers a sale can be reserved for
+const MAX_RESERVED_FOR: usize = 10;
+
 /// approval callbacks from NFT Contracts
 
 #[derive(Serialize, Deserialize)]
@@ -7,6 +10,21 @@ use crate::*;
 pub struct SaleArgs {
     pub sale_conditions: Vec<Price>,
     pub token_type: TokenType,
+    /// nanoseconds since epoch after which the sale can't be bought and anyone can remove it
+    pub expires_at: Option<U64>,
+    /// buyers the sale is reserved for, anyone can buy if not set
+    pub reserved_for: Option<Vec<ValidAccountId>>,
+}
+
+/// custodial listings from NFT Contracts
+pub trait NonFungibleTokenReceiver {
+    fn nft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        previous_owner_id: AccountId,
+        token_id: TokenId,
+        msg: String,
+    ) -> bool;
 }
 
 pub trait NonFungibleTokenApprovalsReceiver {
@@ -22,7 +40,6 @@ pub trait NonFungibleTokenApprovalsReceiver {
 #[near_bindgen]
 impl NonFungibleTokenApprovalsReceiver for Marketplace {
     /// where we add the sale because we know nft owner can only call nft_approve
-
     fn nft_on_approve(
         &mut self,
         token_id: TokenId,
@@ -30,18 +47,134 @@ impl NonFungibleTokenApprovalsReceiver for Marketplace {
         approval_id: U64,
         msg: String,
     ) {
-        let owner_paid_storage = self.storage_deposits.get(owner_id.as_ref()).unwrap_or(0);
-        assert!(
-            owner_paid_storage >= STORAGE_PER_SALE,
-            "Required minimum storage to sell on market: {}",
-            STORAGE_PER_SALE,
+        let nft_contract_id = env::predecessor_account_id();
+        self.assert_nft_contract_allowed(&nft_contract_id);
+        if let Ok(args) = near_sdk::serde_json::from_str::<AcceptCollectionOfferArgs>(&msg) {
+            self.internal_accept_collection_offer(
+                nft_contract_id,
+                token_id,
+                owner_id.into(),
+                approval_id,
+                args,
+            )
+            .as_return();
+            return;
+        }
+        if let Ok(args) = near_sdk::serde_json::from_str::<AcceptOfferArgs>(&msg) {
+            self.internal_accept_offer(
+                nft_contract_id,
+                token_id,
+                owner_id.into(),
+                approval_id,
+                args,
+            )
+            .as_return();
+            return;
+        }
+        if let Ok(args) = near_sdk::serde_json::from_str::<AddToBundleArgs>(&msg) {
+            self.internal_add_to_bundle(
+                nft_contract_id,
+                token_id,
+                owner_id.into(),
+                approval_id,
+                args,
+            );
+            return;
+        }
+        if let Ok(args) = near_sdk::serde_json::from_str::<SwapArgs>(&msg) {
+            if let Some(swap) = self.internal_approve_for_swap(
+                nft_contract_id,
+                token_id,
+                owner_id.into(),
+                approval_id,
+                args,
+            ) {
+                swap.as_return();
+            }
+            return;
+        }
+
+        self.internal_add_sale(
+            nft_contract_id,
+            token_id,
+            owner_id,
+            approval_id,
+            &msg,
+            false,
         );
+    }
+}
 
+#[near_bindgen]
+impl NonFungibleTokenReceiver for Marketplace {
+    /// custodial listing, the marketplace keeps the token until it is sold or the sale removed.
+    /// `msg` are the `SaleArgs` of the sale
+    fn nft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        previous_owner_id: AccountId,
+        token_id: TokenId,
+        msg: String,
+    ) -> bool {
         let nft_contract_id = env::predecessor_account_id();
+        self.assert_nft_contract_allowed(&nft_contract_id);
+        // approved accounts can't set the conditions of someone else's sale
+        assert_eq!(
+            sender_id, previous_owner_id,
+            "Only the owner can list a token in custody"
+        );
+        self.internal_add_sale(
+            nft_contract_id,
+            token_id,
+            ValidAccountId::try_from(previous_owner_id).unwrap(),
+            U64(0),
+            &msg,
+            true,
+        );
+        // keep the token
+        false
+    }
+}
+
+impl Marketplace {
+    /// lists the token, replacing an existing sale of it
+    fn internal_add_sale(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        owner_id: ValidAccountId,
+        approval_id: U64,
+        msg: &str,
+        custodial: bool,
+    ) {
+        self.assert_not_paused(MarketFeature::Listing);
+        // approving again replaces the listing, bids of the previous one go back to escrow
+        self.internal_invalidate_listing(&nft_contract_id, &token_id);
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+
+        let initial_storage_usage = env::storage_usage();
+
         let SaleArgs {
             sale_conditions,
             token_type,
-        } = near_sdk::serde_json::from_str(&msg).expect("Not valid SaleArgs");
+            expires_at,
+            reserved_for,
+        } = near_sdk::serde_json::from_str(msg).expect("Not valid SaleArgs");
+        if let Some(reserved_for) = reserved_for.as_ref() {
+            assert!(
+                !reserved_for.is_empty() && reserved_for.len() <= MAX_RESERVED_FOR,
+                "Sale can be reserved for 1 to {} buyers",
+                MAX_RESERVED_FOR
+            );
+        }
+        if let Some(expires_at) = expires_at {
+            assert!(
+                expires_at.0 > env::block_timestamp(),
+                "Sale expires in the past"
+            );
+            self.sales_by_expiry
+                .insert(&(expires_at.0, contract_and_token_id.clone()), &());
+        }
 
         let mut conditions = HashMap::new();
 
@@ -51,35 +184,61 @@ impl NonFungibleTokenApprovalsReceiver for Marketplace {
             st_symbol,
         } in sale_conditions
         {
-            if !self.ft_token_ids.contains(ft_or_st_token_id.as_ref()) {
+            // sale is denominated in FT,ST or 0 if accepting bids
+            let ask_price_in_ft_or_st = Currency::new(ft_or_st_token_id.as_ref(), st_symbol);
+            if !self.ft_token_ids.contains(&ask_price_in_ft_or_st) {
                 env::panic(
-                    format!("Token {} not supported by this market", ft_or_st_token_id).as_bytes(),
+                    format!(
+                        "Token {} not supported by this market",
+                        ask_price_in_ft_or_st
+                    )
+                    .as_bytes(),
                 );
             }
-            // sale is denominated in FT,ST or 0 if accepting bids
-            let ask_price_in_ft_or_st: String = if let Some(st_symbol) = st_symbol {
-                format!("{}{}{}", ft_or_st_token_id, DELIMETER, st_symbol)
-            } else {
-                ft_or_st_token_id.as_ref().to_string()
-            };
-            conditions.insert(ask_price_in_ft_or_st, price.unwrap_or(U128(0)));
+            let price = price.unwrap_or(U128(0));
+            self.sales_by_price.insert(
+                &(
+                    ask_price_in_ft_or_st.clone(),
+                    price.0,
+                    contract_and_token_id.clone(),
+                ),
+                &(),
+            );
+            conditions.insert(ask_price_in_ft_or_st, price);
         }
 
         // env::log(format!("add_sale for owner: {}", &owner_id).as_bytes());
 
         let bids = HashMap::new();
+        let listed_at = env::block_timestamp();
+        self.sales_by_listed_at
+            .insert(&(listed_at, contract_and_token_id.clone()), &());
 
-        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
-        self.sales.insert(
-            &contract_and_token_id,
-            &Sale {
-                owner_id: owner_id.clone().into(),
-                approval_id,
-                token_type: token_type.clone(),
-                conditions,
-                bids,
-            },
-        );
+        let sale = Sale {
+            owner_id: owner_id.clone().into(),
+            approval_id,
+            token_type: token_type.clone(),
+            conditions,
+            bids,
+            expires_at,
+            reserved_for: reserved_for
+                .map(|reserved_for| reserved_for.into_iter().map(|a| a.into()).collect()),
+            listed_at: U64(listed_at),
+            custodial,
+        };
+        self.sales.insert(&contract_and_token_id, &sale);
+        MarketEvent::SaleCreated(vec![SaleCreatedData {
+            nft_contract_id: nft_contract_id.clone(),
+            token_id: token_id.clone(),
+            owner_id: sale.owner_id,
+            approval_id,
+            token_type: sale.token_type,
+            conditions: sale.conditions,
+            expires_at: sale.expires_at,
+            reserved_for: sale.reserved_for,
+            custodial,
+        }])
+        .emit();
 
         // extra for views
 
@@ -92,12 +251,6 @@ impl NonFungibleTokenApprovalsReceiver for Marketplace {
                 .unwrap(),
             )
         });
-
-        let owner_occupied_storage = u128::from(by_owner_id.len()) * STORAGE_PER_SALE;
-        assert!(
-            owner_paid_storage > owner_occupied_storage,
-            "User has more sales than storage paid"
-        );
         by_owner_id.insert(&contract_and_token_id);
         self.by_owner_id.insert(owner_id.as_ref(), &by_owner_id);
 
@@ -122,11 +275,14 @@ impl NonFungibleTokenApprovalsReceiver for Marketplace {
             //     token_id.contains(&token_type),
             //     "TokenType should be substr of TokenId"
             // );
-            let mut by_nft_token_type =
-                self.by_nft_token_type.get(&token_type).unwrap_or_else(|| {
+            let contract_and_token_type = format!("{}{}{}", nft_contract_id, DELIMETER, token_type);
+            let mut by_nft_token_type = self
+                .by_nft_token_type
+                .get(&contract_and_token_type)
+                .unwrap_or_else(|| {
                     UnorderedSet::new(
                         StorageKey::ByNFTTokenTypeInner {
-                            token_type_hash: hash_account_id(&token_type),
+                            token_type_hash: hash_account_id(&contract_and_token_type),
                         }
                         .try_to_vec()
                         .unwrap(),
@@ -134,9 +290,13 @@ impl NonFungibleTokenApprovalsReceiver for Marketplace {
                 });
             by_nft_token_type.insert(&contract_and_token_id);
             self.by_nft_token_type
-                .insert(&token_type, &by_nft_token_type);
+                .insert(&contract_and_token_type, &by_nft_token_type);
         }
 
-        env::log(b"Successfully added token to marketplace");
+        self.internal_charge_storage(
+            owner_id.as_ref(),
+            &ListingId::Sale(contract_and_token_id),
+            initial_storage_usage,
+        );
     }
 }
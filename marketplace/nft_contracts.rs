@@ -0,0 +1,194 @@
+use crate::*;
+
+/// same scale as `fee_bps`, royalties are limited by the NFT contract anyway
+const MAX_ROYALTY_BPS: u32 = 10_000;
+
+/// rules for the sales of an allowlisted NFT contract
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub struct NFTContractPolicy {
+    /// share of the payout that can go to accounts other than the seller, royalties above it
+    /// are cut down proportionally and the rest is paid to the seller
+    pub max_royalty_bps: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct NFTContractJson {
+    pub nft_contract_id: AccountId,
+    pub policy: NFTContractPolicy,
+    /// overrides the other fees for sales of the contract
+    pub fee_bps: Option<u32>,
+}
+
+#[near_bindgen]
+impl Marketplace {
+    /// only owner, lets the NFT contract list tokens on the marketplace. Adding it again updates the policy
+    pub fn add_nft_contract(
+        &mut self,
+        nft_contract_id: ValidAccountId,
+        policy: Option<NFTContractPolicy>,
+        fee_bps: Option<u32>,
+    ) {
+        self.assert_owner();
+        let policy = policy.unwrap_or_default();
+        if let Some(max_royalty_bps) = policy.max_royalty_bps {
+            assert!(
+                max_royalty_bps <= MAX_ROYALTY_BPS,
+                "Royalty can't be more than {} basis points",
+                MAX_ROYALTY_BPS
+            );
+        }
+        self.nft_contracts.insert(nft_contract_id.as_ref(), &policy);
+        if fee_bps.is_some() {
+            self.set_nft_contract_fee(nft_contract_id, fee_bps);
+        }
+    }
+
+    /// only owner, no new listings or offers. Open sales can't be bought anymore, anyone can
+    /// remove them with `prune_delisted_sales`
+    pub fn remove_nft_contract(&mut self, nft_contract_id: ValidAccountId) -> bool {
+        self.assert_owner();
+        self.fee_bps_by_nft_contract_id
+            .remove(nft_contract_id.as_ref());
+        self.nft_contracts
+            .remove(nft_contract_id.as_ref())
+            .is_some()
+    }
+
+    /// anyone can remove up to `limit` sales of an NFT contract that is no longer allowlisted,
+    /// their bids go back to escrow and custodial tokens to their sellers
+    pub fn prune_delisted_sales(&mut self, nft_contract_id: ValidAccountId, limit: u64) -> u64 {
+        let nft_contract_id: AccountId = nft_contract_id.into();
+        assert!(
+            self.nft_contracts.get(&nft_contract_id).is_none(),
+            "NFT contract is still allowed on this market"
+        );
+        let token_ids: Vec<TokenId> =
+            if let Some(token_ids) = self.by_nft_contract_id.get(&nft_contract_id) {
+                token_ids.iter().take(limit as usize).collect()
+            } else {
+                return 0;
+            };
+        for token_id in token_ids.iter() {
+            let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+            let sale = self.internal_remove_sale(nft_contract_id.clone(), token_id.clone());
+            self.refund_bids(&contract_and_token_id, &sale.bids);
+            if sale.custodial {
+                self.internal_return_custody(
+                    nft_contract_id.clone(),
+                    token_id.clone(),
+                    sale.owner_id,
+                );
+            }
+        }
+        token_ids.len() as u64
+    }
+
+    /// views
+    pub fn is_nft_contract_allowed(&self, nft_contract_id: AccountId) -> bool {
+        self.nft_contracts.get(&nft_contract_id).is_some()
+    }
+
+    /// verified collections, i.e. allowlisted NFT contracts, with their policy and fee
+    pub fn get_nft_contracts(&self, from_index: U64, limit: U64) -> Vec<NFTContractJson> {
+        self.nft_contracts
+            .iter()
+            .skip(from_index.0 as usize)
+            .take(limit.0 as usize)
+            .map(|(nft_contract_id, policy)| NFTContractJson {
+                fee_bps: self.fee_bps_by_nft_contract_id.get(&nft_contract_id),
+                nft_contract_id,
+                policy,
+            })
+            .collect()
+    }
+}
+
+impl Marketplace {
+    pub(crate) fn assert_nft_contract_allowed(&self, nft_contract_id: &AccountId) {
+        if self.nft_contracts.get(nft_contract_id).is_none() {
+            env::panic(
+                format!(
+                    "NFT contract {} not allowed on this market",
+                    nft_contract_id
+                )
+                .as_bytes(),
+            );
+        }
+    }
+
+    /// royalties of `payout` cut down to `max_royalty_bps`, the seller gets the rest of `balance`
+    pub(crate) fn internal_cap_royalties(
+        &self,
+        nft_contract_id: &AccountId,
+        seller_id: &AccountId,
+        mut payout: Payout,
+        balance: Balance,
+    ) -> Payout {
+        if balance == 0
+            || self.internal_payout_within_policy(nft_contract_id, seller_id, &payout, balance)
+        {
+            return payout;
+        }
+        let max_royalty_bps = u128::from(
+            self.nft_contracts
+                .get(nft_contract_id)
+                .and_then(|policy| policy.max_royalty_bps)
+                .unwrap_or(MAX_ROYALTY_BPS),
+        );
+        let royalties = payout
+            .iter()
+            .filter(|(receiver_id, _)| *receiver_id != seller_id)
+            .fold(0u128, |royalties, (_, amount)| {
+                royalties.saturating_add(amount.0)
+            });
+        // rounded up, so the cut royalties stay within the policy
+        let royalty_bps = royalties.saturating_mul(10_000).saturating_add(balance - 1) / balance;
+        env::log(
+            format!(
+                "Royalties of {} basis points cut down to {}",
+                royalty_bps, max_royalty_bps
+            )
+            .as_bytes(),
+        );
+        let mut paid = 0;
+        for (receiver_id, amount) in payout.iter_mut() {
+            if receiver_id != seller_id {
+                amount.0 = amount.0 / royalty_bps * max_royalty_bps
+                    + amount.0 % royalty_bps * max_royalty_bps / royalty_bps;
+                paid += amount.0;
+            }
+        }
+        payout.insert(seller_id.clone(), U128(balance - paid));
+        payout
+    }
+
+    /// `balance` is what the NFT contract was asked to pay out for the token
+    pub(crate) fn internal_payout_within_policy(
+        &self,
+        nft_contract_id: &AccountId,
+        seller_id: &AccountId,
+        payout: &Payout,
+        balance: Balance,
+    ) -> bool {
+        let max_royalty_bps = if let Some(max_royalty_bps) = self
+            .nft_contracts
+            .get(nft_contract_id)
+            .and_then(|policy| policy.max_royalty_bps)
+        {
+            max_royalty_bps
+        } else {
+            return true;
+        };
+        let royalties = payout
+            .iter()
+            .filter(|(receiver_id, _)| *receiver_id != seller_id)
+            .fold(0u128, |royalties, (_, amount)| {
+                royalties.saturating_add(amount.0)
+            });
+        royalties
+            <= balance / 10_000 * u128::from(max_royalty_bps)
+                + balance % 10_000 * u128::from(max_royalty_bps) / 10_000
+    }
+}
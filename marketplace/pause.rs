@@ -0,0 +1,117 @@
+use crate::*;
+
+/// parts of the marketplace that can be paused independently. Removing listings, cancelling
+/// bids and offers, refunds and withdrawals keep working while paused
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde", rename_all = "snake_case")]
+pub enum MarketFeature {
+    /// sales, bundles and swap proposals
+    Listing,
+    /// bids, collection offers and token offers
+    Bidding,
+    /// purchases, accepted bids and offers, swaps and settlements
+    Purchasing,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Default, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PauseStatus {
+    pub listing: bool,
+    pub bidding: bool,
+    pub purchasing: bool,
+}
+
+impl PauseStatus {
+    fn flag(&mut self, feature: MarketFeature) -> &mut bool {
+        match feature {
+            MarketFeature::Listing => &mut self.listing,
+            MarketFeature::Bidding => &mut self.bidding,
+            MarketFeature::Purchasing => &mut self.purchasing,
+        }
+    }
+
+    pub fn is_paused(&self, feature: MarketFeature) -> bool {
+        match feature {
+            MarketFeature::Listing => self.listing,
+            MarketFeature::Bidding => self.bidding,
+            MarketFeature::Purchasing => self.purchasing,
+        }
+    }
+}
+
+#[near_bindgen]
+impl Marketplace {
+    /// only owner or guardian, e.g. to stop trading while a bug is fixed
+    pub fn pause(&mut self, features: Vec<MarketFeature>) {
+        let account_id = env::predecessor_account_id();
+        assert!(
+            account_id == self.owner_id || self.guardians.contains(&account_id),
+            "Owner's or guardian's method"
+        );
+        self.internal_set_paused(features, true, account_id);
+    }
+
+    /// only owner, guardians can't resume what they paused. The marketplace stays paused until
+    /// every sale was rewritten by `migrate_sales`
+    pub fn unpause(&mut self, features: Vec<MarketFeature>) {
+        self.assert_owner();
+        assert_eq!(
+            self.sales_to_migrate, 0,
+            "Sales are still being migrated, call migrate_sales first"
+        );
+        self.internal_set_paused(features, false, env::predecessor_account_id());
+    }
+
+    /// only owner
+    pub fn add_guardian(&mut self, account_id: ValidAccountId) -> bool {
+        self.assert_owner();
+        self.guardians.insert(account_id.as_ref())
+    }
+
+    /// only owner
+    pub fn remove_guardian(&mut self, account_id: ValidAccountId) -> bool {
+        self.assert_owner();
+        self.guardians.remove(account_id.as_ref())
+    }
+
+    /// views
+    pub fn get_pause_status(&self) -> PauseStatus {
+        self.paused.clone()
+    }
+
+    pub fn get_guardians(&self) -> Vec<AccountId> {
+        self.guardians.to_vec()
+    }
+}
+
+impl Marketplace {
+    pub(crate) fn assert_not_paused(&self, feature: MarketFeature) {
+        if self.paused.is_paused(feature) {
+            env::panic(format!("{:?} is paused", feature).as_bytes());
+        }
+    }
+
+    /// emits an event for every flag that changed
+    fn internal_set_paused(
+        &mut self,
+        features: Vec<MarketFeature>,
+        paused: bool,
+        account_id: AccountId,
+    ) {
+        let mut toggled = vec![];
+        for feature in features {
+            let flag = self.paused.flag(feature);
+            if *flag != paused {
+                *flag = paused;
+                toggled.push(PauseData {
+                    feature,
+                    paused,
+                    account_id: account_id.clone(),
+                });
+            }
+        }
+        if !toggled.is_empty() {
+            MarketEvent::PauseUpdated(toggled).emit();
+        }
+    }
+}
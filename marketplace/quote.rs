@@ -0,0 +1,88 @@
+// the quote takes the arguments of `offer` plus the buyer and the NFT contract's payout
+#![allow(clippy::too_many_arguments)]
+
+use crate::*;
+
+/// how buying a sale for `price` would be paid out. The marketplace `fee` is taken first and
+/// the NFT contract pays out `balance`, the rest of the price, for at most `max_len_payout`
+/// receivers. `payout` is what the marketplace would pay for the NFT contract's payout,
+/// `within_limits` is false if it wouldn't be paid as returned
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PurchaseQuote {
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+    pub seller_id: AccountId,
+    pub ft_or_st: Currency,
+    pub price: U128,
+    pub fee: U128,
+    pub balance: U128,
+    pub max_len_payout: u32,
+    pub payout: Option<Payout>,
+    pub within_limits: bool,
+}
+
+#[near_bindgen]
+impl Marketplace {
+    /// quote of `buyer_id` buying or bidding `amount` on a sale, so UIs can warn buyers before
+    /// they sign. Views can't call the NFT contract, so the marketplace doesn't fetch the payout:
+    /// the caller queries the NFT contract's `nft_payout` for `balance` and `max_len_payout` of a
+    /// first quote and passes it as `nft_payout`. Without it `payout` is `None`
+    pub fn quote_purchase(
+        &self,
+        nft_contract_id: ValidAccountId,
+        token_id: TokenId,
+        ft_or_st_token_id: ValidAccountId,
+        st_symbol: Option<String>,
+        amount: U128,
+        buyer_id: ValidAccountId,
+        nft_payout: Option<Payout>,
+    ) -> PurchaseQuote {
+        self.assert_not_paused(MarketFeature::Purchasing);
+        let nft_contract_id: AccountId = nft_contract_id.into();
+        self.assert_nft_contract_allowed(&nft_contract_id);
+        let sale = self
+            .sales
+            .get(&format!("{}{}{}", nft_contract_id, DELIMETER, token_id))
+            .expect("No sale");
+        let ft_or_st = Currency::new(ft_or_st_token_id.as_ref(), st_symbol);
+        sale.assert_buyable(buyer_id.as_ref(), &ft_or_st);
+        let fee = self.internal_fee(&nft_contract_id, &ft_or_st, amount.0);
+        let balance = amount.0 - fee;
+
+        let nft_payout = nft_payout.map(|payout| {
+            if sale.custodial {
+                custodial_payout(payout, &sale.owner_id)
+            } else {
+                payout
+            }
+        });
+        let within_limits = nft_payout
+            .as_ref()
+            .filter(|payout| {
+                payout_is_valid(payout, balance)
+                    && self.internal_payout_within_policy(
+                        &nft_contract_id,
+                        &sale.owner_id,
+                        payout,
+                        balance,
+                    )
+            })
+            .is_some();
+        let payout = nft_payout.map(|payout| {
+            self.internal_purchase_payout(&nft_contract_id, &sale.owner_id, Some(payout), balance)
+        });
+        PurchaseQuote {
+            nft_contract_id,
+            token_id,
+            seller_id: sale.owner_id,
+            ft_or_st,
+            price: amount,
+            fee: U128(fee),
+            balance: U128(balance),
+            max_len_payout: MAX_LEN_SETTLEMENT_PAYOUT,
+            payout,
+            within_limits,
+        }
+    }
+}
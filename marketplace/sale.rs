@@ -1,17 +1,32 @@
 use crate::*;
 use near_sdk::promise_result_as_success;
 
-/// measuring how many royalties can be paid
-const GAS_FOR_FT_TRANSFER: Gas = 5_000_000_000_000;
 /// seems to be max Tgas can attach to resolve_purchase
 const GAS_FOR_ROYALTIES: Gas = 120_000_000_000_000;
-const GAS_FOR_NFT_TRANSFER: Gas = 15_000_000_000_000;
+/// receivers `resolve_purchase` or `continue_settlement` have gas to transfer to,
+/// sale.bids refunds go to escrow and need no transfers
+pub(crate) const MAX_LEN_PAYOUT: u32 = 10;
+/// bids kept per currency of a sale, lowest bid is refunded when a higher one comes in
+const MAX_BIDS_PER_CURRENCY: usize = 10;
 
-#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+/// bids of a sale in each currency, sorted by price from highest to lowest
+pub type Bids = HashMap<Currency, Vec<Bid>>;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
 #[serde(crate = "near_sdk::serde")]
 pub struct Bid {
     pub owner_id: AccountId,
     pub price: U128,
+    /// nanoseconds since epoch after which the bid can't be accepted
+    pub expires_at: Option<U64>,
+}
+
+impl Bid {
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .map(|expires_at| expires_at.0 <= env::block_timestamp())
+            .unwrap_or(false)
+    }
 }
 
 #[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
@@ -20,8 +35,45 @@ pub struct Sale {
     pub owner_id: AccountId,
     pub approval_id: U64,
     pub token_type: Option<String>,
-    pub conditions: HashMap<FTOrSTIdAndStSymbol, U128>,
-    pub bids: HashMap<FTOrSTIdAndStSymbol, Bid>,
+    pub conditions: HashMap<Currency, U128>,
+    pub bids: Bids,
+    /// nanoseconds since epoch after which the sale can't be bought
+    pub expires_at: Option<U64>,
+    /// only these accounts can buy or bid, e.g. for a sale negotiated off-chain
+    pub reserved_for: Option<Vec<AccountId>>,
+    /// block timestamp of the listing
+    pub listed_at: U64,
+    /// the marketplace holds the token, listed with `nft_transfer_call`. `approval_id` is unused
+    pub custodial: bool,
+}
+
+impl Sale {
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .map(|expires_at| expires_at.0 <= env::block_timestamp())
+            .unwrap_or(false)
+    }
+
+    pub fn is_reserved_for(&self, account_id: &AccountId) -> bool {
+        self.reserved_for
+            .as_ref()
+            .map(|reserved_for| reserved_for.contains(account_id))
+            .unwrap_or(true)
+    }
+
+    /// price in `ft_or_st` for `buyer_id`, panics if they can't buy or bid on the sale
+    pub fn assert_buyable(&self, buyer_id: &AccountId, ft_or_st: &Currency) -> U128 {
+        assert_ne!(&self.owner_id, buyer_id, "Cannot buy your own sale.");
+        assert!(!self.is_expired(), "Sale expired");
+        assert!(
+            self.is_reserved_for(buyer_id),
+            "Sale is reserved for another buyer"
+        );
+        *self
+            .conditions
+            .get(ft_or_st)
+            .expect("Not for sale in that token type")
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -37,11 +89,13 @@ pub struct Price {
 pub struct PurchaseArgs {
     pub nft_contract_id: ValidAccountId,
     pub token_id: TokenId,
+    /// only used if the amount ends up as a bid
+    pub expires_at: Option<U64>,
 }
 
 #[near_bindgen]
 impl Marketplace {
-    /// for add sale see: nft_callbacks.rs
+    // for add sale see: nft_callbacks.rs
 
     /// TODO remove without redirect to wallet? panic reverts
     #[payable]
@@ -50,18 +104,45 @@ impl Marketplace {
         let sale = self.internal_remove_sale(nft_contract_id.clone().into(), token_id.clone());
         let owner_id = env::predecessor_account_id();
         assert_eq!(owner_id, sale.owner_id, "Must be sale owner");
-        // revoke access
-        nft_sale::nft_revoke(
-            token_id,
-            ValidAccountId::try_from(MARKETPLACE_ACCOUNT_ID).unwrap(),
-            &nft_contract_id,
-            1,
-            GAS_FOR_NFT_TRANSFER,
-        )
-        .as_return();
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        if sale.custodial {
+            self.internal_return_custody(nft_contract_id.into(), token_id, owner_id)
+                .as_return();
+        } else {
+            // revoke access
+            nft_sale::nft_revoke(
+                token_id,
+                ValidAccountId::try_from(env::current_account_id()).unwrap(),
+                &nft_contract_id,
+                1,
+                GAS_FOR_NFT_TRANSFER,
+            )
+            .as_return();
+        }
         // method calls are atomic in nature. So if assert
         // fails `internal_remove_sale` wont have any affect
-        self.refund_bids(&sale.bids);
+        self.refund_bids(&contract_and_token_id, &sale.bids);
+    }
+
+    /// anyone can remove up to `limit` expired sales, their bids go back to escrow
+    pub fn prune_expired_sales(&mut self, limit: u64) -> u64 {
+        let now = env::block_timestamp();
+        let expired: Vec<ContractAndTokenId> = self
+            .sales_by_expiry
+            .iter()
+            .take_while(|((expires_at, _), _)| *expires_at <= now)
+            .take(limit as usize)
+            .map(|((_, contract_and_token_id), _)| contract_and_token_id)
+            .collect();
+        for contract_and_token_id in expired.iter() {
+            let (nft_contract_id, token_id) = split_contract_and_token_id(contract_and_token_id);
+            let sale = self.internal_remove_sale(nft_contract_id.clone(), token_id.clone());
+            self.refund_bids(contract_and_token_id, &sale.bids);
+            if sale.custodial {
+                self.internal_return_custody(nft_contract_id, token_id, sale.owner_id);
+            }
+        }
+        expired.len() as u64
     }
 
     #[payable]
@@ -83,17 +164,67 @@ impl Marketplace {
             "Must be sale owner"
         );
 
-        let ft_or_st: String = if let Some(st_symbol) = st_symbol {
-            format!("{}{}{}", ft_or_st_token_id, DELIMETER, st_symbol)
-        } else {
-            ft_or_st_token_id.as_ref().to_string()
-        };
+        let ft_or_st = Currency::new(ft_or_st_token_id.as_ref(), st_symbol);
 
         if !self.ft_token_ids.contains(&ft_or_st) {
             env::panic(format!("Token {} not supported by this market", ft_or_st).as_bytes());
         }
-        sale.conditions.insert(ft_or_st_token_id.into(), price);
+        if let Some(old_price) = sale.conditions.insert(ft_or_st.clone(), price) {
+            self.sales_by_price.remove(&(
+                ft_or_st.clone(),
+                old_price.0,
+                contract_and_token_id.clone(),
+            ));
+        }
+        self.sales_by_price
+            .insert(&(ft_or_st, price.0, contract_and_token_id.clone()), &());
         self.sales.insert(&contract_and_token_id, &sale);
+        MarketEvent::SaleUpdated(vec![SaleUpdatedData {
+            nft_contract_id: contract_id,
+            token_id,
+            conditions: sale.conditions,
+        }])
+        .emit();
+    }
+
+    /// sale owner stops selling in given currency, e.g. after it was deprecated. Its bids go to escrow
+    #[payable]
+    pub fn remove_price(
+        &mut self,
+        nft_contract_id: ValidAccountId,
+        token_id: String,
+        ft_or_st_token_id: ValidAccountId,
+        st_symbol: Option<String>,
+    ) {
+        assert_one_yocto();
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let mut sale = self.sales.get(&contract_and_token_id).expect("No sale");
+        assert_eq!(
+            env::predecessor_account_id(),
+            sale.owner_id,
+            "Must be sale owner"
+        );
+
+        let ft_or_st = Currency::new(ft_or_st_token_id.as_ref(), st_symbol);
+        sale.conditions
+            .remove(&ft_or_st)
+            .expect("No price in that token type");
+        assert!(
+            !sale.conditions.is_empty(),
+            "Sale needs a price, remove the sale instead"
+        );
+        if let Some(bids) = sale.bids.remove(&ft_or_st) {
+            let mut refunded: Bids = HashMap::new();
+            refunded.insert(ft_or_st, bids);
+            self.refund_bids(&contract_and_token_id, &refunded);
+        }
+        self.sales.insert(&contract_and_token_id, &sale);
+        MarketEvent::SaleUpdated(vec![SaleUpdatedData {
+            nft_contract_id: nft_contract_id.into(),
+            token_id,
+            conditions: sale.conditions,
+        }])
+        .emit();
     }
 
     #[payable]
@@ -104,147 +235,95 @@ impl Marketplace {
         ft_or_st_token_id: ValidAccountId,
         st_symbol: Option<String>,
         bid_amount: U128,
+        expires_at: Option<U64>,
     ) {
-        assert_one_yocto();
         let contract_id: AccountId = nft_contract_id.into();
         let contract_and_token_id = format!("{}{}{}", contract_id, DELIMETER, token_id);
         let mut sale = self.sales.get(&contract_and_token_id).expect("No sale");
         let buyer_id = env::predecessor_account_id();
-        assert_ne!(sale.owner_id, buyer_id, "Cannot bid on your own sale.");
+        let ask_price_in_ft_or_st = Currency::new(ft_or_st_token_id.as_ref(), st_symbol);
+        let price = sale.assert_buyable(&buyer_id, &ask_price_in_ft_or_st).0;
+        assert!(bid_amount.0 > 0, "Bid amount must be greater than 0");
 
-        let ask_price_in_ft_or_st: String = if let Some(st_symbol) = st_symbol.clone() {
-            format!("{}{}{}", ft_or_st_token_id, DELIMETER, st_symbol)
-        } else {
-            ft_or_st_token_id.to_string()
-        };
-
-        let price = sale
-            .conditions
-            .get(&ask_price_in_ft_or_st.to_string())
-            .expect("Not for sale against given fungible token")
-            .0;
-
-        // let deposit = env::attached_deposit();
-        // assert!(deposit > 0, "Attached deposit must be greater than 0");
-
-        //transfer fungible tokens from current bidder account to marketplace account
-        if let Some(st_symbol) = st_symbol.clone() {
-            nft_sale::st_transfer(
-                st_symbol,
-                Some(ValidAccountId::try_from(env::predecessor_account_id()).unwrap()),
-                MARKETPLACE_ACCOUNT_ID.to_string(),
-                bid_amount,
-                None,
-                &ft_or_st_token_id,
-                1,
-                GAS_FOR_FT_TRANSFER,
-            )
-            .as_return();
-        } else {
-            nft_sale::ft_transfer(
-                Some(ValidAccountId::try_from(env::predecessor_account_id()).unwrap()),
-                MARKETPLACE_ACCOUNT_ID.to_string(),
-                bid_amount,
-                None,
-                &ft_or_st_token_id,
-                1,
-                GAS_FOR_FT_TRANSFER,
-            )
-            .as_return();
-        }
-        // there's a fixed price user can buy for
-        if bid_amount == price.into() {
+        // funds were pushed to escrow with `ft_transfer_call` or `st_transfer_call`,
+        // the bid or purchase only uses what the marketplace already holds
+        self.internal_take_payment(
+            &buyer_id,
+            &ask_price_in_ft_or_st,
+            bid_amount.0,
+            "bid amount",
+        );
+        // there's a fixed price user can buy for, a price of 0 only takes bids
+        if price > 0 && bid_amount.0 == price {
             self.process_purchase(
                 contract_id,
                 token_id,
-                ft_or_st_token_id.to_string(),
-                st_symbol.clone(),
+                ask_price_in_ft_or_st,
                 bid_amount,
                 buyer_id,
+                true,
             );
         } else {
             self.add_bid(
                 contract_and_token_id,
                 price,
-                bid_amount.into(),
-                ft_or_st_token_id.to_string(),
-                st_symbol,
-                buyer_id,
+                ask_price_in_ft_or_st,
+                Bid {
+                    owner_id: buyer_id,
+                    price: bid_amount,
+                    expires_at,
+                },
                 &mut sale,
             )
         }
     }
 
-    #[private]
-    pub fn add_bid(
+    /// bid owner takes back the bid, funds go to their escrow
+    #[payable]
+    pub fn cancel_bid(
         &mut self,
-        contract_and_token_id: ContractAndTokenId,
-        price: Balance,
-        amount: Balance,
-        ft_or_st_token_id: AccountId,
+        nft_contract_id: ValidAccountId,
+        token_id: String,
+        ft_or_st_token_id: ValidAccountId,
         st_symbol: Option<String>,
-        buyer_id: AccountId,
-        sale: &mut Sale,
     ) {
-        assert!(
-            price == 0 || amount < price,
-            "Paid more {} than price {}",
-            amount,
-            price
-        );
-        // store a bid and refund any current bid lower
-        let new_bid = Bid {
-            owner_id: buyer_id,
-            price: U128(amount),
-        };
+        assert_one_yocto();
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let mut sale = self.sales.get(&contract_and_token_id).expect("No sale");
+        let ft_or_st = Currency::new(ft_or_st_token_id.as_ref(), st_symbol);
+        let bid = remove_bid(&mut sale.bids, &ft_or_st, &env::predecessor_account_id())
+            .expect("Must be bid owner");
+        self.internal_refund_bid(&contract_and_token_id, &ft_or_st, &bid);
+        self.sales.insert(&contract_and_token_id, &sale);
+    }
 
-        let bid_key = if let Some(st_symbol) = st_symbol.clone() {
-            format!("{}{}{}", ft_or_st_token_id, DELIMETER, st_symbol)
-        } else {
-            ft_or_st_token_id.clone()
-        };
-        let current_bid = sale.bids.get(&bid_key);
-        if let Some(current_bid) = current_bid {
-            // refund current bid holder
-            let current_price: u128 = current_bid.price.into();
-            assert!(
-                amount > current_price,
-                "Can't pay less than or equal to current bid price: {}",
-                current_price
-            );
-            // TODO check if promise fails what to do.
-            //return FT or ST of previous bidder
-            if let Some(st_symbol) = st_symbol.clone() {
-                nft_sale::st_transfer(
-                    st_symbol,
-                    None,
-                    current_bid.owner_id.clone(),
-                    current_bid.price.into(),
-                    None,
-                    &ft_or_st_token_id,
-                    1,
-                    GAS_FOR_FT_TRANSFER,
-                )
-                .as_return();
-            } else {
-                nft_sale::ft_transfer(
-                    None,
-                    current_bid.owner_id.clone(),
-                    current_bid.price.into(),
-                    None,
-                    &ft_or_st_token_id,
-                    1,
-                    GAS_FOR_FT_TRANSFER,
-                )
-                .as_return();
-            }
+    /// anyone can remove expired bids of a sale, funds go to the escrow of bidders
+    pub fn prune_expired_bids(&mut self, nft_contract_id: ValidAccountId, token_id: String) -> u64 {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let mut sale = self.sales.get(&contract_and_token_id).expect("No sale");
+        let expired = take_expired_bids(&mut sale.bids);
+        self.refund_bids(&contract_and_token_id, &expired);
+        self.sales.insert(&contract_and_token_id, &sale);
+        expired.values().map(|bids| bids.len() as u64).sum()
+    }
 
-            // Promise::new(current_bid.owner_id.clone()).transfer(current_bid.price.into());
-            sale.bids.insert(bid_key, new_bid);
-        } else {
-            sale.bids.insert(bid_key, new_bid);
-        }
+    /// anyone can move bids in deprecated currencies to escrow, returns the number of refunded bids
+    pub fn refund_deprecated_bids(
+        &mut self,
+        nft_contract_id: ValidAccountId,
+        token_id: String,
+    ) -> u64 {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let mut sale = self.sales.get(&contract_and_token_id).expect("No sale");
+        let deprecated_ft_token_ids = &self.deprecated_ft_token_ids;
+        let (deprecated, active): (Bids, Bids) = sale
+            .bids
+            .drain()
+            .partition(|(ft_or_st, _)| deprecated_ft_token_ids.contains(ft_or_st));
+        sale.bids = active;
+        self.refund_bids(&contract_and_token_id, &deprecated);
         self.sales.insert(&contract_and_token_id, &sale);
+        deprecated.values().map(|bids| bids.len() as u64).sum()
     }
 
     pub fn accept_offer(
@@ -253,200 +332,465 @@ impl Marketplace {
         token_id: String,
         ft_or_st_token_id: ValidAccountId,
         st_symbol: Option<String>,
+        bidder_id: Option<ValidAccountId>,
     ) {
         let contract_id: AccountId = nft_contract_id.into();
-        let contract_and_token_id =
-            format!("{}{}{}", contract_id.clone(), DELIMETER, token_id.clone());
+        let contract_and_token_id = format!("{}{}{}", contract_id, DELIMETER, token_id);
         // remove bid before proceeding to process purchase
         let mut sale = self.sales.get(&contract_and_token_id).expect("No sale");
+        assert_eq!(
+            env::predecessor_account_id(),
+            sale.owner_id,
+            "Must be sale owner"
+        );
+        assert!(!sale.is_expired(), "Sale expired");
 
-        let bid_price_in_ft_or_st: String = if let Some(st_symbol) = st_symbol.clone() {
-            format!("{}{}{}", ft_or_st_token_id, DELIMETER, st_symbol)
-        } else {
-            ft_or_st_token_id.as_ref().to_string()
-        };
+        let bid_price_in_ft_or_st = Currency::new(ft_or_st_token_id.as_ref(), st_symbol);
+
+        // expired bids go back to escrow and are never accepted
+        let expired = take_expired_bids(&mut sale.bids);
+        self.refund_bids(&contract_and_token_id, &expired);
 
-        let bid = sale.bids.remove(&bid_price_in_ft_or_st).expect("No bid");
+        // highest active bid unless seller picks a specific bidder
+        let bidder_id: AccountId = match bidder_id {
+            Some(bidder_id) => bidder_id.into(),
+            None => sale
+                .bids
+                .get(&bid_price_in_ft_or_st)
+                .and_then(|bids| bids.first())
+                .expect("No bid")
+                .owner_id
+                .clone(),
+        };
+        let bid = remove_bid(&mut sale.bids, &bid_price_in_ft_or_st, &bidder_id).expect("No bid");
         self.sales.insert(&contract_and_token_id, &sale);
-        // panics at `self.internal_remove_sale` and reverts above if predecessor is not sale.owner_id
+        self.internal_release_storage(
+            &bidder_id,
+            &ListingId::Bid(
+                contract_and_token_id.clone(),
+                bid_price_in_ft_or_st.clone(),
+                bidder_id.clone(),
+            ),
+        );
+        MarketEvent::BidAccepted(vec![BidData::new(
+            &contract_and_token_id,
+            &bid_price_in_ft_or_st,
+            &bid,
+        )])
+        .emit();
         self.process_purchase(
             contract_id,
             token_id,
-            ft_or_st_token_id.into(),
-            st_symbol,
-            bid.price.clone(),
-            bid.owner_id.clone(),
+            bid_price_in_ft_or_st,
+            bid.price,
+            bid.owner_id,
+            true,
         );
     }
 
+    /// self callback
+
     #[private]
-    pub fn process_purchase(
+    pub fn resolve_purchase(
+        &mut self,
+        sold: SoldToken,
+        ft_or_st: Currency,
+        buyer_id: AccountId,
+        price: U128,
+        fee: U128,
+        refund_to_escrow: bool,
+    ) -> U128 {
+        // None means the token wasn't transferred, the buyer is refunded
+        let transfer_result = promise_result_as_success();
+        let mut purchase = PurchaseData {
+            nft_contract_id: sold.nft_contract_id.clone(),
+            token_id: sold.token_id.clone(),
+            seller_id: sold.seller_id.clone(),
+            buyer_id: buyer_id.clone(),
+            ft_or_st: ft_or_st.clone(),
+            price,
+            fee,
+            payout: None,
+        };
+        let payout = if let Some(value) = transfer_result {
+            // the token moved to the buyer, so the price is paid out whatever the payout is
+            let payout_option =
+                near_sdk::serde_json::from_slice::<Payout>(&value)
+                    .ok()
+                    .map(|payout| {
+                        if sold.custodial {
+                            custodial_payout(payout, &sold.seller_id)
+                        } else {
+                            payout
+                        }
+                    });
+            let payout = self.internal_purchase_payout(
+                &sold.nft_contract_id,
+                &sold.seller_id,
+                payout_option,
+                price.0 - fee.0,
+            );
+            purchase.payout = Some(payout.clone());
+            MarketEvent::PurchaseCompleted(vec![purchase]).emit();
+            payout
+        } else {
+            MarketEvent::PurchaseFailed(vec![purchase]).emit();
+            // the sale is gone, a custodial token that wasn't transferred goes back to the seller
+            if sold.custodial {
+                self.internal_return_custody(
+                    sold.nft_contract_id.clone(),
+                    sold.token_id.clone(),
+                    sold.seller_id.clone(),
+                );
+            }
+            // funds held by the marketplace (bids, offers) go back to the buyer's escrow
+            if refund_to_escrow {
+                self.internal_escrow_deposit(&buyer_id, &ft_or_st, price.0);
+                return U128(0);
+            }
+            if ft_or_st == Currency::Near {
+                Promise::new(buyer_id).transfer(u128::from(price));
+            }
+            // leave function and return all FTs in ft_resolve_transfer
+            return price;
+        };
+        // Goint to payout everyone, outstanding bids were already escrowed in `process_purchase`
+        self.internal_treasury_deposit(&ft_or_st, fee.0);
+        let contract_and_token_id =
+            format!("{}{}{}", sold.nft_contract_id, DELIMETER, sold.token_id);
+        self.internal_record_trade(Trade {
+            nft_contract_id: sold.nft_contract_id,
+            token_id: sold.token_id,
+            token_type: sold.token_type,
+            seller_id: sold.seller_id,
+            buyer_id: buyer_id.clone(),
+            ft_or_st: ft_or_st.clone(),
+            price,
+            timestamp: U64(env::block_timestamp()),
+        });
+
+        // NEAR, FT or ST payouts
+        self.internal_pay_out(
+            vec![contract_and_token_id],
+            buyer_id,
+            ft_or_st.clone(),
+            payout,
+        );
+        if ft_or_st == Currency::Near {
+            // refund all FTs (won't be any)
+            price
+        } else {
+            // keep all FTs or STs (already transferred for payouts)
+            U128(0)
+        }
+    }
+}
+
+impl Marketplace {
+    pub(crate) fn add_bid(
+        &mut self,
+        contract_and_token_id: ContractAndTokenId,
+        price: Balance,
+        ft_or_st: Currency,
+        new_bid: Bid,
+        sale: &mut Sale,
+    ) {
+        self.assert_not_paused(MarketFeature::Bidding);
+        assert!(
+            !self.deprecated_ft_token_ids.contains(&ft_or_st),
+            "Token {} is deprecated",
+            ft_or_st
+        );
+        let amount = new_bid.price.0;
+        assert!(
+            price == 0 || amount < price,
+            "Paid more {} than price {}",
+            amount,
+            price
+        );
+        assert!(!new_bid.is_expired(), "Bid expires in the past");
+
+        let bids = sale.bids.remove(&ft_or_st).unwrap_or_default();
+        // one bid per bidder, expired bids make room for new ones
+        let (mut refunds, mut bids): (Vec<Bid>, Vec<Bid>) = bids
+            .into_iter()
+            .partition(|bid| bid.owner_id == new_bid.owner_id || bid.is_expired());
+
+        if bids.len() >= MAX_BIDS_PER_CURRENCY {
+            let lowest_price = bids.last().unwrap().price.0;
+            assert!(
+                amount > lowest_price,
+                "Can't pay less than or equal to lowest bid price: {}",
+                lowest_price
+            );
+            refunds.push(bids.pop().unwrap());
+        }
+        // previous bidders can withdraw FT or ST from escrow, refunds come first so a replaced
+        // bid releases its storage before the new one is charged
+        for bid in refunds {
+            self.internal_refund_bid(&contract_and_token_id, &ft_or_st, &bid);
+        }
+
+        // bids with the same price keep their order, first come first served
+        let position = bids
+            .iter()
+            .position(|bid| bid.price.0 < amount)
+            .unwrap_or(bids.len());
+        let placed = BidData::new(&contract_and_token_id, &ft_or_st, &new_bid);
+        let bidder_id = new_bid.owner_id.clone();
+        if !bids.is_empty() {
+            sale.bids.insert(ft_or_st.clone(), bids.clone());
+        }
+        self.sales.insert(&contract_and_token_id, sale);
+        // the bidder is charged the bytes the bid adds to the sale
+        let initial_storage_usage = env::storage_usage();
+        bids.insert(position, new_bid);
+        sale.bids.insert(ft_or_st.clone(), bids);
+        self.sales.insert(&contract_and_token_id, sale);
+        self.internal_charge_storage(
+            &bidder_id,
+            &ListingId::Bid(contract_and_token_id.clone(), ft_or_st, bidder_id.clone()),
+            initial_storage_usage,
+        );
+        MarketEvent::BidPlaced(vec![placed]).emit();
+    }
+
+    /// `refund_to_escrow` is set when the marketplace already holds the buyer's funds
+    pub(crate) fn process_purchase(
         &mut self,
         nft_contract_id: AccountId,
         token_id: String,
-        ft_or_st_token_id: AccountId,
-        st_symbol: Option<String>,
+        ft_or_st: Currency,
         price: U128,
         buyer_id: AccountId,
+        refund_to_escrow: bool,
     ) -> Promise {
         let sale = self.internal_remove_sale(nft_contract_id.clone(), token_id.clone());
+        // sale is over whatever the outcome of the transfer, other bids go back to escrow
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        self.refund_bids(&contract_and_token_id, &sale.bids);
+        self.internal_transfer_payout(
+            SoldToken {
+                nft_contract_id,
+                token_id,
+                token_type: sale.token_type,
+                seller_id: sale.owner_id,
+                custodial: sale.custodial,
+            },
+            if sale.custodial {
+                None
+            } else {
+                Some(sale.approval_id)
+            },
+            ft_or_st,
+            Bid {
+                owner_id: buyer_id,
+                price,
+                expires_at: None,
+            },
+            refund_to_escrow,
+        )
+    }
 
+    /// transfers the token to the owner of the winning bid and pays out the sale in `resolve_purchase`
+    pub(crate) fn internal_transfer_payout(
+        &self,
+        sold: SoldToken,
+        approval_id: Option<U64>,
+        ft_or_st: Currency,
+        bid: Bid,
+        refund_to_escrow: bool,
+    ) -> Promise {
+        self.assert_not_paused(MarketFeature::Purchasing);
+        self.assert_nft_contract_allowed(&sold.nft_contract_id);
+        let fee = self.internal_fee(&sold.nft_contract_id, &ft_or_st, bid.price.0);
         nft_sale::nft_transfer_payout(
-            buyer_id.clone(),
-            token_id,
-            sale.approval_id,
+            bid.owner_id.clone(),
+            sold.token_id.clone(),
+            approval_id,
             None,
-            price,
-            &nft_contract_id,
+            U128(bid.price.0 - fee),
+            &sold.nft_contract_id,
             1,
             GAS_FOR_NFT_TRANSFER,
         )
         .then(ext_self::resolve_purchase(
-            ft_or_st_token_id,
-            st_symbol,
-            buyer_id,
-            sale,
-            price,
+            sold,
+            ft_or_st,
+            bid.owner_id,
+            bid.price,
+            U128(fee),
+            refund_to_escrow,
             &env::current_account_id(),
             NO_DEPOSIT,
             GAS_FOR_ROYALTIES,
         ))
     }
 
-    /// self callback
+    /// what is paid out for a token transferred to the buyer, `balance` is the price without
+    /// the marketplace fee. The token already moved, so a payout of the NFT contract that can't
+    /// be paid goes whole to the seller, and royalties above the policy are cut down to it
+    pub(crate) fn internal_purchase_payout(
+        &self,
+        nft_contract_id: &AccountId,
+        seller_id: &AccountId,
+        payout: Option<Payout>,
+        balance: Balance,
+    ) -> Payout {
+        match payout {
+            Some(payout) if payout_is_valid(&payout, balance) => {
+                self.internal_cap_royalties(nft_contract_id, seller_id, payout, balance)
+            }
+            _ => {
+                env::log(b"Invalid payout, the seller is paid the whole balance");
+                [(seller_id.clone(), U128(balance))]
+                    .iter()
+                    .cloned()
+                    .collect()
+            }
+        }
+    }
 
-    #[private]
-    pub fn resolve_purchase(
+    /// sends a custodial token back to the seller
+    pub(crate) fn internal_return_custody(
+        &self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        owner_id: AccountId,
+    ) -> Promise {
+        nft_sale::nft_transfer(
+            owner_id,
+            token_id,
+            None,
+            None,
+            &nft_contract_id,
+            1,
+            GAS_FOR_NFT_TRANSFER,
+        )
+    }
+
+    /// a new approval of the token, or its sale elsewhere, ends its listing. Bids go to escrow
+    pub(crate) fn internal_invalidate_listing(
         &mut self,
-        ft_or_st_token_id: AccountId,
-        st_symbol: Option<String>,
+        nft_contract_id: &AccountId,
+        token_id: &TokenId,
+    ) {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        if self.sales.get(&contract_and_token_id).is_some() {
+            let sale = self.internal_remove_sale(nft_contract_id.clone(), token_id.clone());
+            self.refund_bids(&contract_and_token_id, &sale.bids);
+        }
+    }
+
+    /// sells the token to an accepted collection or token offer, the NFT contract confirmed
+    /// the approval. A failed transfer credits the offer funds to `buyer_id`'s escrow
+    pub(crate) fn internal_fill_offer(
+        &mut self,
+        sold: SoldToken,
+        approval_id: U64,
+        ft_or_st: Currency,
         buyer_id: AccountId,
-        sale: Sale,
         price: U128,
-    ) -> U128 {
-        // checking for payout information
-        let payout_option = promise_result_as_success().and_then(|value| {
-            // None means a bad payout from bad NFT contract
-            near_sdk::serde_json::from_slice::<Payout>(&value)
-                .ok()
-                .and_then(|payout| {
-                    // gas to do 10 FT transfers (and definitely 10 NEAR transfers)
-                    if payout.len() + sale.bids.len() > 10 || payout.is_empty() {
-                        env::log(
-                            format!("Cannot have more than 10 royalties and sale.bids refunds")
-                                .as_bytes(),
-                        );
-                        None
-                    } else {
-                        // TODO off by 1 e.g. payouts are fractions of 3333 + 3333 + 3333
-                        let mut remainder = price.0;
-                        for &value in payout.values() {
-                            remainder = remainder.checked_sub(value.0)?;
-                        }
-                        if remainder == 0 || remainder == 1 {
-                            Some(payout)
-                        } else {
-                            None
-                        }
-                    }
-                })
-        });
-        // is payout option valid?
-        let payout = if let Some(payout_option) = payout_option {
-            payout_option
-        } else {
-            if ft_or_st_token_id == "near" {
-                Promise::new(buyer_id).transfer(u128::from(price));
-            }
-            // leave function and return all FTs in ft_resolve_transfer
-            return price;
-        };
-        // Goint to payout everyone, first return all outstanding bids (accepted offer bid was already removed)
-        self.refund_bids(&sale.bids);
+    ) -> Promise {
+        self.internal_invalidate_listing(&sold.nft_contract_id, &sold.token_id);
+        self.internal_transfer_payout(
+            sold,
+            Some(approval_id),
+            ft_or_st,
+            Bid {
+                owner_id: buyer_id,
+                price,
+                expires_at: None,
+            },
+            true,
+        )
+    }
 
-        // NEAR payouts
-        if ft_or_st_token_id == "near" {
-            for (receiver_id, amount) in payout {
-                Promise::new(receiver_id).transfer(amount.0);
+    /// moves bids to the escrow of their owners, see escrow.rs for withdrawal
+    pub(crate) fn refund_bids(&mut self, contract_and_token_id: &str, bids: &Bids) {
+        for (bid_ft_or_st, bids) in bids {
+            for bid in bids {
+                self.internal_refund_bid(contract_and_token_id, bid_ft_or_st, bid);
             }
-            // refund all FTs (won't be any)
-            price
-        } else if ft_or_st_token_id == FT_ACCOUNT_ID {
-            // FT payouts
-            for (receiver_id, amount) in payout {
-                nft_sale::ft_transfer(
-                    None,
-                    receiver_id,
-                    amount,
-                    None,
-                    &ft_or_st_token_id,
-                    1,
-                    GAS_FOR_FT_TRANSFER,
-                );
-            }
-            // keep all FTs (already transferred for payouts)
-            U128(0)
-        } else {
-            // ST payouts
-            for (receiver_id, amount) in payout {
-                nft_sale::st_transfer(
-                    st_symbol.clone().expect("no symbol"),
-                    None,
-                    receiver_id,
-                    amount,
-                    None,
-                    &ft_or_st_token_id,
-                    1,
-                    GAS_FOR_FT_TRANSFER,
-                );
-            }
-            // keep all STs (already transferred for payouts)
-            U128(0)
         }
     }
 
-    fn refund_bids(&mut self, bids: &HashMap<FTOrSTIdAndStSymbol, Bid>) {
-        for (bid_ft_or_st, bid) in bids {
-            if bid_ft_or_st == "near" {
-                Promise::new(bid.owner_id.clone()).transfer(u128::from(bid.price));
-            } else if bid_ft_or_st == FT_ACCOUNT_ID {
-                nft_sale::ft_transfer(
-                    None,
-                    bid.owner_id.clone(),
-                    bid.price,
-                    None,
-                    bid_ft_or_st,
-                    1,
-                    GAS_FOR_FT_TRANSFER,
-                );
-            } else {
-                let st_account_id_and_symbol_vec =
-                    bid_ft_or_st.split(DELIMETER).collect::<Vec<_>>();
-                nft_sale::st_transfer(
-                    st_account_id_and_symbol_vec[1].to_string(),
-                    None,
-                    bid.owner_id.clone(),
-                    bid.price,
-                    None,
-                    &st_account_id_and_symbol_vec[0].to_string(),
-                    1,
-                    GAS_FOR_FT_TRANSFER,
-                );
-            }
-        }
+    pub(crate) fn internal_refund_bid(
+        &mut self,
+        contract_and_token_id: &str,
+        ft_or_st: &Currency,
+        bid: &Bid,
+    ) {
+        self.internal_release_storage(
+            &bid.owner_id,
+            &ListingId::Bid(
+                contract_and_token_id.to_string(),
+                ft_or_st.clone(),
+                bid.owner_id.clone(),
+            ),
+        );
+        self.internal_escrow_deposit(&bid.owner_id, ft_or_st, bid.price.0);
+        MarketEvent::BidRefunded(vec![BidData::new(contract_and_token_id, ft_or_st, bid)]).emit();
     }
 }
 
-/// self call
+/// the NFT contract pays the owner's share of a custodial token to the marketplace,
+/// it goes to the seller
+pub(crate) fn custodial_payout(mut payout: Payout, seller_id: &AccountId) -> Payout {
+    if let Some(share) = payout.remove(&env::current_account_id()) {
+        payout.entry(seller_id.clone()).or_insert(U128(0)).0 += share.0;
+    }
+    payout
+}
 
-#[ext_contract(ext_self)]
-trait ExtSelf {
-    fn resolve_purchase(
-        &mut self,
-        ft_or_st_token_id: AccountId,
-        st_symbol: Option<String>,
-        buyer_id: AccountId,
-        sale: Sale,
-        price: U128,
-    ) -> Promise;
+/// whether `payout` can be paid out as returned by the NFT contract for `balance`
+pub(crate) fn payout_is_valid(payout: &Payout, balance: Balance) -> bool {
+    // larger payouts than `MAX_LEN_PAYOUT` are paid in pages, see settlement.rs
+    if payout.len() > MAX_LEN_SETTLEMENT_PAYOUT as usize || payout.is_empty() {
+        env::log(
+            format!(
+                "Cannot have more than {} royalties",
+                MAX_LEN_SETTLEMENT_PAYOUT
+            )
+            .as_bytes(),
+        );
+        return false;
+    }
+    // TODO off by 1 e.g. payouts are fractions of 3333 + 3333 + 3333
+    let remainder = payout
+        .values()
+        .try_fold(balance, |remainder, value| remainder.checked_sub(value.0));
+    matches!(remainder, Some(0) | Some(1))
+}
+
+/// removes the expired bids of every currency, returning them
+fn take_expired_bids(bids: &mut Bids) -> Bids {
+    let mut expired: Bids = HashMap::new();
+    for (ft_or_st, currency_bids) in bids.iter_mut() {
+        let (expired_bids, active_bids): (Vec<Bid>, Vec<Bid>) =
+            currency_bids.drain(..).partition(|bid| bid.is_expired());
+        *currency_bids = active_bids;
+        if !expired_bids.is_empty() {
+            expired.insert(ft_or_st.clone(), expired_bids);
+        }
+    }
+    bids.retain(|_, currency_bids| !currency_bids.is_empty());
+    expired
+}
+
+/// takes the bid of `owner_id` in given currency out of the order book
+pub(crate) fn remove_bid(
+    bids: &mut Bids,
+    ft_or_st: &Currency,
+    owner_id: &AccountId,
+) -> Option<Bid> {
+    let currency_bids = bids.get_mut(ft_or_st)?;
+    let position = currency_bids
+        .iter()
+        .position(|bid| &bid.owner_id == owner_id)?;
+    let bid = currency_bids.remove(position);
+    if currency_bids.is_empty() {
+        bids.remove(ft_or_st);
+    }
+    Some(bid)
 }
@@ -1,4 +1,9 @@
 use crate::*;
+use near_sdk::collections::Vector;
+use std::ops::Bound;
+
+/// sales read by `get_collection_stats` to find the floor price
+const FLOOR_PRICE_SCAN: usize = 50;
 
 #[derive(Serialize, Deserialize)]
 #[serde(crate = "near_sdk::serde")]
@@ -7,21 +12,46 @@ pub struct SaleJson {
     pub token_id: TokenId,
     pub token_type: TokenType,
     pub owner_id: AccountId,
-    pub conditions: HashMap<FTOrSTIdAndStSymbol, U128>,
-    pub bids: HashMap<FTOrSTIdAndStSymbol, Bid>,
+    pub conditions: HashMap<Currency, U128>,
+    pub bids: Bids,
+    pub expires_at: Option<U64>,
+    pub reserved_for: Option<Vec<AccountId>>,
+    pub listed_at: U64,
+    pub custodial: bool,
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum SaleSort {
+    PriceAsc,
+    PriceDesc,
+    ListedAsc,
+    ListedDesc,
+}
+
+/// every given field must match, price range and price sort need a currency
+#[derive(Serialize, Deserialize, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SaleFilter {
+    pub nft_contract_id: Option<AccountId>,
+    pub token_type: TokenType,
+    pub ft_or_st_token_id: Option<ValidAccountId>,
+    pub st_symbol: Option<String>,
+    pub min_price: Option<U128>,
+    pub max_price: Option<U128>,
+    pub sort: Option<SaleSort>,
 }
 
 #[near_bindgen]
 impl Marketplace {
     /// views
-
     pub fn get_sales_by_owner_id(
         &self,
         account_id: AccountId,
         from_index: U64,
         limit: U64,
     ) -> Vec<SaleJson> {
-        let mut tmp = vec![];
         let by_owner_id = self.by_owner_id.get(&account_id);
         let sales = if let Some(by_owner_id) = by_owner_id {
             by_owner_id
@@ -31,28 +61,13 @@ impl Marketplace {
         let keys = sales.as_vector();
         let start = u64::from(from_index);
         let end = min(start + u64::from(limit), sales.len());
-        for i in start..end {
-            let contract_and_token_id = keys.get(i).unwrap();
-            let strings: Vec<&str> = contract_and_token_id.split(&DELIMETER).collect();
-            let nft_contract_id = strings[0].to_string();
-            let token_id = strings[1].to_string();
-            let Sale {
-                approval_id: _,
-                owner_id,
-                token_type,
-                conditions,
-                bids,
-            } = self.sales.get(&contract_and_token_id).unwrap();
-            tmp.push(SaleJson {
-                nft_contract_id: nft_contract_id.clone(),
-                token_id,
-                token_type,
-                owner_id,
-                conditions,
-                bids,
-            });
-        }
-        tmp
+        (start..end)
+            .map(|i| {
+                let contract_and_token_id = keys.get(i).unwrap();
+                let sale = self.sales.get(&contract_and_token_id).unwrap();
+                sale_json(&contract_and_token_id, sale)
+            })
+            .collect()
     }
 
     pub fn get_sales_by_nft_contract_id(
@@ -61,7 +76,6 @@ impl Marketplace {
         from_index: U64,
         limit: U64,
     ) -> Vec<SaleJson> {
-        let mut tmp = vec![];
         let by_nft_contract_id = self.by_nft_contract_id.get(&nft_contract_id);
         let sales = if let Some(by_nft_contract_id) = by_nft_contract_id {
             by_nft_contract_id
@@ -71,38 +85,27 @@ impl Marketplace {
         let keys = sales.as_vector();
         let start = u64::from(from_index);
         let end = min(start + u64::from(limit), sales.len());
-        for i in start..end {
-            let token_id = keys.get(i).unwrap();
-            let Sale {
-                approval_id: _,
-                owner_id,
-                token_type,
-                conditions,
-                bids,
-            } = self
-                .sales
-                .get(&format!("{}{}{}", &nft_contract_id, DELIMETER, &token_id))
-                .unwrap();
-            tmp.push(SaleJson {
-                nft_contract_id: nft_contract_id.clone(),
-                token_id,
-                token_type,
-                owner_id,
-                conditions,
-                bids,
-            });
-        }
-        tmp
+        (start..end)
+            .map(|i| {
+                let contract_and_token_id =
+                    format!("{}{}{}", &nft_contract_id, DELIMETER, keys.get(i).unwrap());
+                let sale = self.sales.get(&contract_and_token_id).unwrap();
+                sale_json(&contract_and_token_id, sale)
+            })
+            .collect()
     }
 
+    /// sales of one token type of an NFT contract
     pub fn get_sales_by_nft_token_type(
         &self,
+        nft_contract_id: AccountId,
         token_type: String,
         from_index: U64,
         limit: U64,
     ) -> Vec<SaleJson> {
-        let mut tmp = vec![];
-        let by_nft_token_type = self.by_nft_token_type.get(&token_type);
+        let by_nft_token_type = self
+            .by_nft_token_type
+            .get(&format!("{}{}{}", nft_contract_id, DELIMETER, token_type));
         let sales = if let Some(by_nft_token_type) = by_nft_token_type {
             by_nft_token_type
         } else {
@@ -111,31 +114,282 @@ impl Marketplace {
         let keys = sales.as_vector();
         let start = u64::from(from_index);
         let end = min(start + u64::from(limit), sales.len());
-        for i in start..end {
-            let contract_and_token_id = keys.get(i).unwrap();
-            let strings: Vec<&str> = contract_and_token_id.split(&DELIMETER).collect();
-            let nft_contract_id = strings[0].to_string();
-            let token_id = strings[1].to_string();
-            let Sale {
-                approval_id: _,
-                owner_id,
-                token_type,
-                conditions,
-                bids,
-            } = self.sales.get(&contract_and_token_id).unwrap();
-            tmp.push(SaleJson {
-                nft_contract_id: nft_contract_id.clone(),
-                token_id,
-                token_type,
-                owner_id,
-                conditions,
-                bids,
-            });
-        }
-        tmp
+        (start..end)
+            .map(|i| {
+                let contract_and_token_id = keys.get(i).unwrap();
+                let sale = self.sales.get(&contract_and_token_id).unwrap();
+                sale_json(&contract_and_token_id, sale)
+            })
+            .collect()
     }
 
     pub fn get_sale(&self, nft_contract_token: ContractAndTokenId) -> Option<Sale> {
         self.sales.get(&nft_contract_token)
     }
+
+    /// sales priced or bid on in given currency, e.g. the ones affected by its deprecation.
+    /// Scans `limit` sales from `from_index` of all sales
+    pub fn get_sales_by_ft_or_st(
+        &self,
+        ft_or_st_token_id: ValidAccountId,
+        st_symbol: Option<String>,
+        from_index: U64,
+        limit: U64,
+    ) -> Vec<SaleJson> {
+        let ft_or_st = Currency::new(ft_or_st_token_id.as_ref(), st_symbol);
+        let keys = self.sales.keys_as_vector();
+        let start = u64::from(from_index);
+        let end = min(start + u64::from(limit), keys.len());
+        (start..end)
+            .filter_map(|i| {
+                let contract_and_token_id = keys.get(i).unwrap();
+                let sale = self.sales.get(&contract_and_token_id).unwrap();
+                if sale.conditions.contains_key(&ft_or_st) || sale.bids.contains_key(&ft_or_st) {
+                    Some(sale_json(&contract_and_token_id, sale))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// sales matching the filter. `from_index` and `limit` apply to the index the search reads,
+    /// not to the matching sales, so a page can hold fewer than `limit` sales and the next one
+    /// starts at `from_index + limit`. Unsorted results are read from the narrowest index the
+    /// filter allows, newest listings first without one
+    pub fn search_sales(&self, filter: SaleFilter, from_index: U64, limit: U64) -> Vec<SaleJson> {
+        let ft_or_st = filter.ft_or_st_token_id.as_ref().map(|ft_or_st_token_id| {
+            Currency::new(ft_or_st_token_id.as_ref(), filter.st_symbol.clone())
+        });
+        let min_price = filter.min_price.map(|price| price.0).unwrap_or(0);
+        let max_price = filter.max_price.map(|price| price.0).unwrap_or(u128::MAX);
+        assert!(
+            ft_or_st.is_some() || (filter.min_price.is_none() && filter.max_price.is_none()),
+            "Price range needs a currency"
+        );
+        assert!(min_price <= max_price, "Invalid price range");
+
+        let skip = from_index.0 as usize;
+        let take = limit.0 as usize;
+        let candidates: Vec<ContractAndTokenId> = match filter.sort {
+            Some(SaleSort::PriceAsc) | Some(SaleSort::PriceDesc) => {
+                let ft_or_st = ft_or_st
+                    .as_ref()
+                    .expect("Sorting by price needs a currency");
+                self.internal_sales_by_price(
+                    ft_or_st.clone(),
+                    min_price,
+                    max_price,
+                    filter.sort == Some(SaleSort::PriceDesc),
+                )
+                .skip(skip)
+                .take(take)
+                .collect()
+            }
+            Some(SaleSort::ListedAsc) => self
+                .sales_by_listed_at
+                .iter()
+                .skip(skip)
+                .take(take)
+                .map(|((_, contract_and_token_id), _)| contract_and_token_id)
+                .collect(),
+            Some(SaleSort::ListedDesc) => self.internal_latest_sales(skip, take),
+            None => match (&filter.nft_contract_id, &filter.token_type, &ft_or_st) {
+                (Some(nft_contract_id), Some(token_type), _) => self
+                    .by_nft_token_type
+                    .get(&format!("{}{}{}", nft_contract_id, DELIMETER, token_type))
+                    .map(|sales| page(sales.as_vector(), from_index, limit))
+                    .unwrap_or_default(),
+                (Some(nft_contract_id), None, _) => self
+                    .by_nft_contract_id
+                    .get(nft_contract_id)
+                    .map(|token_ids| page(token_ids.as_vector(), from_index, limit))
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|token_id| format!("{}{}{}", nft_contract_id, DELIMETER, token_id))
+                    .collect(),
+                (None, _, Some(ft_or_st)) => self
+                    .internal_sales_by_price(ft_or_st.clone(), min_price, max_price, false)
+                    .skip(skip)
+                    .take(take)
+                    .collect(),
+                (None, _, None) => self.internal_latest_sales(skip, take),
+            },
+        };
+
+        candidates
+            .into_iter()
+            .filter_map(|contract_and_token_id| {
+                let sale = self.sales.get(&contract_and_token_id)?;
+                if !sale_matches(
+                    &filter,
+                    &ft_or_st,
+                    min_price,
+                    max_price,
+                    &contract_and_token_id,
+                    &sale,
+                ) {
+                    return None;
+                }
+                Some(sale_json(&contract_and_token_id, sale))
+            })
+            .collect()
+    }
+
+    /// bids of a sale in given currency, highest first
+    pub fn get_bids(
+        &self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        ft_or_st_token_id: ValidAccountId,
+        st_symbol: Option<String>,
+    ) -> Vec<Bid> {
+        self.sales
+            .get(&format!("{}{}{}", nft_contract_id, DELIMETER, token_id))
+            .and_then(|mut sale| {
+                sale.bids
+                    .remove(&Currency::new(ft_or_st_token_id.as_ref(), st_symbol))
+            })
+            .unwrap_or_default()
+    }
+}
+
+impl Marketplace {
+    /// `limit` sales from `from_index` of the newest listings
+    fn internal_latest_sales(&self, from_index: usize, limit: usize) -> Vec<ContractAndTokenId> {
+        self.sales_by_listed_at
+            .iter_rev()
+            .skip(from_index)
+            .take(limit)
+            .map(|((_, contract_and_token_id), _)| contract_and_token_id)
+            .collect()
+    }
+
+    /// cheapest price above 0 in `ft_or_st` among the `FLOOR_PRICE_SCAN` cheapest sales in it
+    /// that match the filter, sales at 0 only take bids
+    pub(crate) fn internal_floor_price(
+        &self,
+        filter: &SaleFilter,
+        ft_or_st: &Currency,
+    ) -> Option<U128> {
+        self.internal_sales_by_price(ft_or_st.clone(), 1, u128::MAX, false)
+            .take(FLOOR_PRICE_SCAN)
+            .find_map(|contract_and_token_id| {
+                let sale = self.sales.get(&contract_and_token_id)?;
+                if sale_matches(filter, &None, 0, u128::MAX, &contract_and_token_id, &sale) {
+                    sale.conditions.get(ft_or_st).copied()
+                } else {
+                    None
+                }
+            })
+    }
+
+    /// sales priced in the currency within the range, cheapest first unless `rev`
+    fn internal_sales_by_price(
+        &self,
+        ft_or_st: Currency,
+        min_price: Balance,
+        max_price: Balance,
+        rev: bool,
+    ) -> Box<dyn Iterator<Item = ContractAndTokenId> + '_> {
+        // first key after every sale of the currency priced at most `max_price`
+        let upper = if max_price < u128::MAX {
+            (ft_or_st.clone(), max_price + 1, String::new())
+        } else {
+            (currency_upper_bound(&ft_or_st), 0, String::new())
+        };
+        let lower = (ft_or_st.clone(), min_price, String::new());
+        if rev {
+            Box::new(
+                self.sales_by_price
+                    .iter_rev_from(upper)
+                    .take_while(move |((key_ft_or_st, price, _), _)| {
+                        *key_ft_or_st == ft_or_st && *price >= min_price
+                    })
+                    .map(|((_, _, contract_and_token_id), _)| contract_and_token_id),
+            )
+        } else {
+            Box::new(
+                self.sales_by_price
+                    .range((Bound::Included(lower), Bound::Excluded(upper)))
+                    .map(|((_, _, contract_and_token_id), _)| contract_and_token_id),
+            )
+        }
+    }
+}
+
+/// whether the sale matches the contract, token type and price range of the filter
+fn sale_matches(
+    filter: &SaleFilter,
+    ft_or_st: &Option<Currency>,
+    min_price: Balance,
+    max_price: Balance,
+    contract_and_token_id: &str,
+    sale: &Sale,
+) -> bool {
+    let (nft_contract_id, _) = split_contract_and_token_id(contract_and_token_id);
+    if filter
+        .nft_contract_id
+        .as_ref()
+        .map(|filter_contract_id| *filter_contract_id != nft_contract_id)
+        .unwrap_or(false)
+    {
+        return false;
+    }
+    if filter.token_type.is_some() && filter.token_type != sale.token_type {
+        return false;
+    }
+    if let Some(ft_or_st) = ft_or_st.as_ref() {
+        match sale.conditions.get(ft_or_st) {
+            Some(price) => price.0 >= min_price && price.0 <= max_price,
+            None => false,
+        }
+    } else {
+        true
+    }
+}
+
+/// `limit` elements from `from_index` of an index
+fn page<T: BorshDeserialize>(vector: &Vector<T>, from_index: U64, limit: U64) -> Vec<T> {
+    let start = u64::from(from_index);
+    let end = min(start.saturating_add(u64::from(limit)), vector.len());
+    (start..end).map(|i| vector.get(i).unwrap()).collect()
+}
+
+/// smallest currency ordered after `ft_or_st`, bounding its keys in `sales_by_price`
+fn currency_upper_bound(ft_or_st: &Currency) -> Currency {
+    match ft_or_st {
+        Currency::Near => Currency::Ft(String::new()),
+        Currency::Ft(ft_token_id) => Currency::Ft(format!("{}\0", ft_token_id)),
+        Currency::St(st_token_id, st_symbol) => {
+            Currency::St(st_token_id.clone(), format!("{}\0", st_symbol))
+        }
+    }
+}
+
+fn sale_json(contract_and_token_id: &str, sale: Sale) -> SaleJson {
+    let (nft_contract_id, token_id) = split_contract_and_token_id(contract_and_token_id);
+    let Sale {
+        approval_id: _,
+        owner_id,
+        token_type,
+        conditions,
+        bids,
+        expires_at,
+        reserved_for,
+        listed_at,
+        custodial,
+    } = sale;
+    SaleJson {
+        nft_contract_id,
+        token_id,
+        token_type,
+        owner_id,
+        conditions,
+        bids,
+        expires_at,
+        reserved_for,
+        listed_at,
+        custodial,
+    }
 }
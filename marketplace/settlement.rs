@@ -0,0 +1,92 @@
+use crate::*;
+
+/// receivers a purchase can be paid out to, paid `MAX_LEN_PAYOUT` at a time
+pub(crate) const MAX_LEN_SETTLEMENT_PAYOUT: u32 = 100;
+
+/// payout of a purchase too large to transfer in `resolve_purchase`. The tokens are already
+/// with the buyer and the marketplace holds the funds still owed to `payout`
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Settlement {
+    pub settlement_id: U64,
+    pub tokens: Vec<ContractAndTokenId>,
+    pub buyer_id: AccountId,
+    pub ft_or_st: Currency,
+    pub payout: Vec<(AccountId, U128)>,
+}
+
+#[near_bindgen]
+impl Marketplace {
+    /// anyone can pay out the next receivers of a pending settlement, returns `true` once
+    /// the settlement is finished
+    pub fn continue_settlement(&mut self, settlement_id: U64) -> bool {
+        self.assert_not_paused(MarketFeature::Purchasing);
+        let settlement = self
+            .settlements
+            .get(&settlement_id.0)
+            .expect("No settlement");
+        self.internal_settle(settlement)
+    }
+
+    /// views
+    pub fn get_settlement(&self, settlement_id: U64) -> Option<Settlement> {
+        self.settlements.get(&settlement_id.0)
+    }
+
+    pub fn get_settlements(&self, from_index: U64, limit: U64) -> Vec<Settlement> {
+        let values = self.settlements.values_as_vector();
+        let start = u64::from(from_index);
+        let end = min(start + u64::from(limit), values.len());
+        (start..end).map(|i| values.get(i).unwrap()).collect()
+    }
+}
+
+impl Marketplace {
+    /// pays out a purchase, in one go or through a settlement finished by `continue_settlement`
+    pub(crate) fn internal_pay_out(
+        &mut self,
+        tokens: Vec<ContractAndTokenId>,
+        buyer_id: AccountId,
+        ft_or_st: Currency,
+        payout: Payout,
+    ) {
+        if payout.len() <= MAX_LEN_PAYOUT as usize {
+            for (receiver_id, amount) in payout {
+                transfer_ft_or_st(&ft_or_st, receiver_id, amount);
+            }
+            return;
+        }
+        let settlement_id = self.next_settlement_id;
+        self.next_settlement_id += 1;
+        let mut payout: Vec<(AccountId, U128)> = payout.into_iter().collect();
+        // largest amounts are paid first
+        payout.sort_by_key(|(_, amount)| amount.0);
+        env::log(format!("Settlement {} pending", settlement_id).as_bytes());
+        self.internal_settle(Settlement {
+            settlement_id: U64(settlement_id),
+            tokens,
+            buyer_id,
+            ft_or_st,
+            payout,
+        });
+    }
+
+    /// pays out the next `MAX_LEN_PAYOUT` receivers, keeps the settlement until it is finished
+    fn internal_settle(&mut self, mut settlement: Settlement) -> bool {
+        let start = settlement
+            .payout
+            .len()
+            .saturating_sub(MAX_LEN_PAYOUT as usize);
+        for (receiver_id, amount) in settlement.payout.drain(start..) {
+            transfer_ft_or_st(&settlement.ft_or_st, receiver_id, amount);
+        }
+        if settlement.payout.is_empty() {
+            self.settlements.remove(&settlement.settlement_id.0);
+            true
+        } else {
+            self.settlements
+                .insert(&settlement.settlement_id.0, &settlement);
+            false
+        }
+    }
+}
@@ -0,0 +1,27 @@
+use crate::*;
+
+/// callbacks from ST Contracts
+pub trait SocialTokensReceiver {
+    fn st_on_transfer(
+        &mut self,
+        token_symbol: String,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128>;
+}
+
+#[near_bindgen]
+impl SocialTokensReceiver for Marketplace {
+    /// same messages as `ft_on_transfer`, the currency is the symbol of the calling ST contract
+    fn st_on_transfer(
+        &mut self,
+        token_symbol: String,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        let ft_or_st = Currency::St(env::predecessor_account_id(), token_symbol);
+        self.internal_on_transfer(ft_or_st, sender_id, amount, msg)
+    }
+}
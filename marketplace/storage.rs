@@ -0,0 +1,313 @@
+use crate::*;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+
+/// locked while the account is registered, covers its entries in the storage ledgers
+const STORAGE_FOR_REGISTRATION: u128 = 250 * STORAGE_PRICE_PER_BYTE;
+
+// NEP-145 storage management. Listings (sales, bundles, swap proposals, offers and bids) and
+// escrow balances are charged the storage they actually use, measured when they are created and
+// released when they are removed. Escrow credited by the marketplace itself, e.g. refunded bids,
+// is recorded without requiring a deposit, the owner can't take back their storage deposit
+// until it's withdrawn
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StorageBalance {
+    pub total: U128,
+    pub available: U128,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StorageBalanceBounds {
+    pub min: U128,
+    pub max: Option<U128>,
+}
+
+/// entry whose storage is charged to its owner
+#[derive(BorshDeserialize, BorshSerialize)]
+pub enum ListingId {
+    Sale(ContractAndTokenId),
+    Bundle(u64),
+    Swap(u64),
+    TokenOffer(u64),
+    CollectionOffer(u64),
+    Bid(ContractAndTokenId, Currency, AccountId),
+    Escrow(AccountId),
+}
+
+#[near_bindgen]
+impl Marketplace {
+    /// registers the account with the minimum balance, any further deposit can be spent on listings
+    #[payable]
+    pub fn storage_deposit(
+        &mut self,
+        account_id: Option<ValidAccountId>,
+        registration_only: Option<bool>,
+    ) -> StorageBalance {
+        let storage_account_id: AccountId = account_id
+            .map(|a| a.into())
+            .unwrap_or_else(env::predecessor_account_id);
+        let deposit = env::attached_deposit();
+        let registration_only = registration_only.unwrap_or(false);
+        let balance = if let Some(balance) = self.storage_deposits.get(&storage_account_id) {
+            if registration_only {
+                env::log(b"The account is already registered, refunding the deposit");
+                if deposit > 0 {
+                    Promise::new(env::predecessor_account_id()).transfer(deposit);
+                }
+                balance
+            } else {
+                balance + deposit
+            }
+        } else {
+            assert!(
+                deposit >= STORAGE_FOR_REGISTRATION,
+                "Requires minimum deposit of {}",
+                STORAGE_FOR_REGISTRATION
+            );
+            if registration_only {
+                let refund = deposit - STORAGE_FOR_REGISTRATION;
+                if refund > 0 {
+                    Promise::new(env::predecessor_account_id()).transfer(refund);
+                }
+                STORAGE_FOR_REGISTRATION
+            } else {
+                deposit
+            }
+        };
+        self.storage_deposits.insert(&storage_account_id, &balance);
+        self.internal_storage_balance(&storage_account_id, balance)
+    }
+
+    /// withdraws `amount`, or all the deposit open listings don't need
+    #[payable]
+    pub fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalance {
+        assert_one_yocto();
+        let owner_id = env::predecessor_account_id();
+        let balance = self
+            .storage_deposits
+            .get(&owner_id)
+            .expect("The account is not registered");
+        let available = self.internal_storage_available(&owner_id, balance);
+        let amount = amount.map(|amount| amount.0).unwrap_or(available);
+        assert!(
+            amount <= available,
+            "The amount is greater than the available storage balance"
+        );
+        let balance = balance - amount;
+        self.storage_deposits.insert(&owner_id, &balance);
+        if amount > 0 {
+            Promise::new(owner_id.clone()).transfer(amount);
+        }
+        self.internal_storage_balance(&owner_id, balance)
+    }
+
+    /// refunds the whole deposit, with `force` open listings of the account are removed first
+    #[payable]
+    pub fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        assert_one_yocto();
+        let owner_id = env::predecessor_account_id();
+        let balance = if let Some(balance) = self.storage_deposits.get(&owner_id) {
+            balance
+        } else {
+            return false;
+        };
+        if self.storage_usage_by_owner_id.get(&owner_id).is_some() {
+            // funds of bids, collection offers and escrow are only taken back by their owner
+            assert!(
+                self.escrow.get(&owner_id).is_none(),
+                "Can't unregister the account with bids, collection offers or escrow balances"
+            );
+            assert!(
+                force.unwrap_or(false),
+                "Can't unregister the account with open listings, remove them or use force"
+            );
+            self.internal_remove_listings(&owner_id);
+            // only escrow credited for the removed swap top-ups and offers is left
+            let escrow = self
+                .storage_usage_by_listing_id
+                .get(&ListingId::Escrow(owner_id.clone()))
+                .unwrap_or(0);
+            assert_eq!(
+                self.storage_usage_by_owner_id.get(&owner_id).unwrap_or(0),
+                escrow,
+                "Can't unregister the account with bids, collection offers or escrow balances"
+            );
+        }
+        self.storage_deposits.remove(&owner_id);
+        Promise::new(owner_id).transfer(balance);
+        true
+    }
+
+    /// views
+    pub fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        StorageBalanceBounds {
+            min: U128(STORAGE_FOR_REGISTRATION),
+            max: None,
+        }
+    }
+
+    pub fn storage_balance_of(&self, account_id: ValidAccountId) -> Option<StorageBalance> {
+        self.storage_deposits
+            .get(account_id.as_ref())
+            .map(|balance| self.internal_storage_balance(account_id.as_ref(), balance))
+    }
+
+    /// rough storage cost of one sale, the actual usage is measured when listing
+    pub fn storage_amount(&self) -> U128 {
+        U128(STORAGE_PER_SALE)
+    }
+
+    pub fn storage_paid(&self, account_id: ValidAccountId) -> U128 {
+        U128(self.storage_deposits.get(account_id.as_ref()).unwrap_or(0))
+    }
+
+    /// bytes used by the open listings, bids and escrow balances of the account
+    pub fn storage_used(&self, account_id: ValidAccountId) -> U64 {
+        U64(self
+            .storage_usage_by_owner_id
+            .get(account_id.as_ref())
+            .unwrap_or(0))
+    }
+}
+
+impl Marketplace {
+    /// charges the owner for storage used since `initial_storage_usage` by the listing
+    pub(crate) fn internal_charge_storage(
+        &mut self,
+        owner_id: &AccountId,
+        listing_id: &ListingId,
+        initial_storage_usage: u64,
+    ) {
+        self.internal_record_storage(owner_id, listing_id, initial_storage_usage);
+        self.internal_assert_storage_balance(owner_id);
+    }
+
+    /// records storage used since `initial_storage_usage` against the owner without
+    /// requiring their deposit to cover it
+    pub(crate) fn internal_record_storage(
+        &mut self,
+        owner_id: &AccountId,
+        listing_id: &ListingId,
+        initial_storage_usage: u64,
+    ) {
+        // entries are written before measuring so their own storage is charged too
+        let charged = self
+            .storage_usage_by_listing_id
+            .get(listing_id)
+            .unwrap_or(0);
+        self.storage_usage_by_listing_id
+            .insert(listing_id, &charged);
+        let used = self.storage_usage_by_owner_id.get(owner_id).unwrap_or(0);
+        self.storage_usage_by_owner_id.insert(owner_id, &used);
+
+        let bytes = env::storage_usage().saturating_sub(initial_storage_usage);
+        self.storage_usage_by_listing_id
+            .insert(listing_id, &(charged + bytes));
+        self.storage_usage_by_owner_id
+            .insert(owner_id, &(used + bytes));
+    }
+
+    pub(crate) fn internal_assert_storage_balance(&self, owner_id: &AccountId) {
+        let balance = self.storage_deposits.get(owner_id).unwrap_or(0);
+        let used = self.storage_usage_by_owner_id.get(owner_id).unwrap_or(0);
+        let required = STORAGE_FOR_REGISTRATION + Balance::from(used) * STORAGE_PRICE_PER_BYTE;
+        assert!(
+            balance >= required,
+            "Not enough storage deposit, required {}",
+            required
+        );
+    }
+
+    /// releases whatever was charged for the listing
+    pub(crate) fn internal_release_storage(
+        &mut self,
+        owner_id: &AccountId,
+        listing_id: &ListingId,
+    ) {
+        self.internal_release_storage_bytes(owner_id, listing_id, u64::MAX);
+    }
+
+    /// releases up to `bytes` of what was charged for the listing
+    pub(crate) fn internal_release_storage_bytes(
+        &mut self,
+        owner_id: &AccountId,
+        listing_id: &ListingId,
+        bytes: u64,
+    ) {
+        let charged = if let Some(charged) = self.storage_usage_by_listing_id.get(listing_id) {
+            charged
+        } else {
+            return;
+        };
+        let bytes = bytes.min(charged);
+        if bytes == charged {
+            self.storage_usage_by_listing_id.remove(listing_id);
+        } else {
+            self.storage_usage_by_listing_id
+                .insert(listing_id, &(charged - bytes));
+        }
+        let used = self
+            .storage_usage_by_owner_id
+            .get(owner_id)
+            .unwrap_or(0)
+            .saturating_sub(bytes);
+        if used == 0 {
+            self.storage_usage_by_owner_id.remove(owner_id);
+        } else {
+            self.storage_usage_by_owner_id.insert(owner_id, &used);
+        }
+    }
+
+    fn internal_storage_available(&self, owner_id: &AccountId, balance: Balance) -> Balance {
+        let used = self.storage_usage_by_owner_id.get(owner_id).unwrap_or(0);
+        balance
+            .saturating_sub(STORAGE_FOR_REGISTRATION + Balance::from(used) * STORAGE_PRICE_PER_BYTE)
+    }
+
+    fn internal_storage_balance(&self, owner_id: &AccountId, balance: Balance) -> StorageBalance {
+        StorageBalance {
+            total: U128(balance),
+            available: U128(self.internal_storage_available(owner_id, balance)),
+        }
+    }
+
+    /// removes every sale, bundle, swap proposal and token offer of the account, funds go to escrow
+    fn internal_remove_listings(&mut self, owner_id: &AccountId) {
+        if let Some(sales) = self.by_owner_id.get(owner_id) {
+            for contract_and_token_id in sales.to_vec() {
+                let (nft_contract_id, token_id) =
+                    split_contract_and_token_id(&contract_and_token_id);
+                let sale = self.internal_remove_sale(nft_contract_id.clone(), token_id.clone());
+                self.refund_bids(&contract_and_token_id, &sale.bids);
+                if sale.custodial {
+                    self.internal_return_custody(nft_contract_id, token_id, sale.owner_id);
+                }
+            }
+        }
+        if let Some(bundles) = self.bundles_by_owner_id.get(owner_id) {
+            for bundle_id in bundles.to_vec() {
+                self.internal_remove_bundle(bundle_id);
+            }
+        }
+        if let Some(swaps) = self.swaps_by_proposer_id.get(owner_id) {
+            for swap_id in swaps.to_vec() {
+                let swap = self.internal_remove_swap(swap_id);
+                if let Some(top_up) = swap.top_up {
+                    self.internal_escrow_deposit(
+                        &swap.proposer_id,
+                        &top_up.ft_or_st,
+                        top_up.amount.0,
+                    );
+                }
+            }
+        }
+        if let Some(offers) = self.token_offers_by_bidder_id.get(owner_id) {
+            for offer_id in offers.to_vec() {
+                let offer = self.internal_remove_token_offer(offer_id);
+                self.internal_escrow_deposit(&offer.owner_id, &offer.ft_or_st, offer.price.0);
+            }
+        }
+    }
+}
@@ -0,0 +1,347 @@
+use crate::*;
+use near_sdk::PromiseResult;
+
+/// tokens of one side of a swap
+const MAX_SWAP_TOKENS: usize = 3;
+/// moves every token to its new owner and pays the top-up
+const GAS_FOR_RESOLVE_SWAP: Gas = 120_000_000_000_000;
+
+/// `approval_id` is set once the owner approved the marketplace for the swap
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapToken {
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+    pub approval_id: Option<U64>,
+}
+
+/// fungible amount the proposer adds to their side, held by the marketplace
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TopUp {
+    pub ft_or_st: Currency,
+    pub amount: U128,
+}
+
+/// proposer trades `offered` (and the top-up) for `wanted` of the counterparty.
+/// Executed as soon as every token of both sides is approved
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Swap {
+    pub swap_id: U64,
+    pub proposer_id: AccountId,
+    pub counterparty_id: AccountId,
+    pub offered: Vec<SwapToken>,
+    pub wanted: Vec<SwapToken>,
+    pub top_up: Option<TopUp>,
+}
+
+/// `nft_approve` msg approving a token for one side of a swap
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapArgs {
+    pub swap_id: U64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapTokenJson {
+    pub nft_contract_id: ValidAccountId,
+    pub token_id: TokenId,
+}
+
+#[near_bindgen]
+impl Marketplace {
+    /// NEAR top-up is paid with the attached deposit, FT and ST top-up from the caller's escrow
+    #[payable]
+    pub fn propose_swap(
+        &mut self,
+        counterparty_id: ValidAccountId,
+        offered: Vec<SwapTokenJson>,
+        wanted: Vec<SwapTokenJson>,
+        top_up_ft_or_st_token_id: Option<ValidAccountId>,
+        top_up_st_symbol: Option<String>,
+        top_up: Option<U128>,
+    ) -> U64 {
+        self.assert_not_paused(MarketFeature::Listing);
+        let proposer_id = env::predecessor_account_id();
+        let counterparty_id: AccountId = counterparty_id.into();
+        assert_ne!(proposer_id, counterparty_id, "Cannot swap with yourself");
+        assert!(
+            !offered.is_empty() && !wanted.is_empty(),
+            "Both sides of a swap need tokens"
+        );
+        assert!(
+            offered.len() <= MAX_SWAP_TOKENS && wanted.len() <= MAX_SWAP_TOKENS,
+            "Cannot have more than {} tokens on one side of a swap",
+            MAX_SWAP_TOKENS
+        );
+
+        let top_up = match (top_up_ft_or_st_token_id, top_up) {
+            (Some(ft_or_st_token_id), Some(amount)) => {
+                let ft_or_st = Currency::new(ft_or_st_token_id.as_ref(), top_up_st_symbol);
+                if !self.ft_token_ids.contains(&ft_or_st) {
+                    env::panic(
+                        format!("Token {} not supported by this market", ft_or_st).as_bytes(),
+                    );
+                }
+                assert!(amount.0 > 0, "Top-up must be greater than 0");
+                self.internal_take_payment(&proposer_id, &ft_or_st, amount.0, "top-up");
+                Some(TopUp { ft_or_st, amount })
+            }
+            (None, None) => None,
+            _ => env::panic(b"Top-up needs both a token and an amount"),
+        };
+
+        for token in offered.iter().chain(wanted.iter()) {
+            self.assert_nft_contract_allowed(token.nft_contract_id.as_ref());
+        }
+        let to_swap_tokens = |tokens: Vec<SwapTokenJson>| -> Vec<SwapToken> {
+            tokens
+                .into_iter()
+                .map(|token| SwapToken {
+                    nft_contract_id: token.nft_contract_id.into(),
+                    token_id: token.token_id,
+                    approval_id: None,
+                })
+                .collect()
+        };
+        // after the top-up, so storage freed in escrow isn't taken off the charge
+        let initial_storage_usage = env::storage_usage();
+        let swap_id = self.next_swap_id;
+        self.next_swap_id += 1;
+        let swap = Swap {
+            swap_id: U64(swap_id),
+            proposer_id: proposer_id.clone(),
+            counterparty_id: counterparty_id.clone(),
+            offered: to_swap_tokens(offered),
+            wanted: to_swap_tokens(wanted),
+            top_up,
+        };
+        self.swaps.insert(&swap_id, &swap);
+        add_id_by_account_id(
+            &mut self.swaps_by_proposer_id,
+            &proposer_id,
+            swap_id,
+            |account_id_hash| StorageKey::SwapsByProposerIdInner { account_id_hash },
+        );
+        add_id_by_account_id(
+            &mut self.swaps_by_counterparty_id,
+            &counterparty_id,
+            swap_id,
+            |account_id_hash| StorageKey::SwapsByCounterpartyIdInner { account_id_hash },
+        );
+        // approvals only fill in `approval_id`, the proposer is charged for the whole swap
+        self.internal_charge_storage(
+            &proposer_id,
+            &ListingId::Swap(swap_id),
+            initial_storage_usage,
+        );
+
+        MarketEvent::SwapProposed(vec![swap]).emit();
+        U64(swap_id)
+    }
+
+    /// proposer withdraws or counterparty declines the swap, top-up goes to the proposer's escrow
+    #[payable]
+    pub fn cancel_swap(&mut self, swap_id: U64) {
+        assert_one_yocto();
+        let swap = self.internal_remove_swap(swap_id.0);
+        let account_id = env::predecessor_account_id();
+        assert!(
+            account_id == swap.proposer_id || account_id == swap.counterparty_id,
+            "Must be proposer or counterparty"
+        );
+        if let Some(top_up) = swap.top_up {
+            self.internal_escrow_deposit(&swap.proposer_id, &top_up.ft_or_st, top_up.amount.0);
+        }
+    }
+
+    /// self callback, every token was transferred to the marketplace or the swap is rolled back
+    #[private]
+    pub fn resolve_swap(&mut self, swap: Swap) {
+        let tokens: Vec<(&SwapToken, &AccountId, &AccountId)> = swap
+            .offered
+            .iter()
+            .map(|token| (token, &swap.proposer_id, &swap.counterparty_id))
+            .chain(
+                swap.wanted
+                    .iter()
+                    .map(|token| (token, &swap.counterparty_id, &swap.proposer_id)),
+            )
+            .collect();
+        let succeeded: Vec<bool> = (0..tokens.len())
+            .map(|index| {
+                matches!(
+                    env::promise_result(index as u64),
+                    PromiseResult::Successful(_)
+                )
+            })
+            .collect();
+        let valid = succeeded.iter().all(|succeeded| *succeeded);
+        if valid {
+            MarketEvent::SwapCompleted(vec![swap.clone()]).emit();
+        } else {
+            MarketEvent::SwapFailed(vec![swap.clone()]).emit();
+        }
+
+        // tokens held by the marketplace go to the other side, or back on failure
+        let mut deliveries: HashMap<AccountId, Vec<HeldToken>> = HashMap::new();
+        for ((token, owner_id, receiver_id), succeeded) in tokens.into_iter().zip(succeeded) {
+            if !succeeded {
+                continue;
+            }
+            let receiver_id = if valid { receiver_id } else { owner_id };
+            deliveries
+                .entry(receiver_id.clone())
+                .or_default()
+                .push(HeldToken {
+                    nft_contract_id: token.nft_contract_id.clone(),
+                    token_id: token.token_id.clone(),
+                });
+        }
+        for (receiver_id, tokens) in deliveries {
+            deliver_tokens(receiver_id, tokens);
+        }
+
+        // top-up is credited to escrow, a failed transfer can't lose it
+        if let Some(top_up) = swap.top_up {
+            let receiver_id = if valid {
+                &swap.counterparty_id
+            } else {
+                env::log(b"Swap failed, top-up credited to proposer's escrow");
+                &swap.proposer_id
+            };
+            self.internal_escrow_deposit(receiver_id, &top_up.ft_or_st, top_up.amount.0);
+        }
+    }
+
+    /// views
+    pub fn get_swap(&self, swap_id: U64) -> Option<Swap> {
+        self.swaps.get(&swap_id.0)
+    }
+
+    pub fn get_swaps_by_proposer_id(
+        &self,
+        account_id: AccountId,
+        from_index: U64,
+        limit: U64,
+    ) -> Vec<Swap> {
+        self.internal_get_swaps(
+            self.swaps_by_proposer_id.get(&account_id),
+            from_index,
+            limit,
+        )
+    }
+
+    pub fn get_swaps_by_counterparty_id(
+        &self,
+        account_id: AccountId,
+        from_index: U64,
+        limit: U64,
+    ) -> Vec<Swap> {
+        self.internal_get_swaps(
+            self.swaps_by_counterparty_id.get(&account_id),
+            from_index,
+            limit,
+        )
+    }
+}
+
+impl Marketplace {
+    /// called from `nft_on_approve`, executes the swap once its last token is approved
+    pub(crate) fn internal_approve_for_swap(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        owner_id: AccountId,
+        approval_id: U64,
+        args: SwapArgs,
+    ) -> Option<Promise> {
+        let mut swap = self.swaps.get(&args.swap_id.0).expect("No swap");
+        let tokens = if owner_id == swap.proposer_id {
+            &mut swap.offered
+        } else if owner_id == swap.counterparty_id {
+            &mut swap.wanted
+        } else {
+            env::panic(b"Must be proposer or counterparty")
+        };
+        let token = tokens
+            .iter_mut()
+            .find(|token| token.nft_contract_id == nft_contract_id && token.token_id == token_id)
+            .expect("Token is not part of the swap");
+        token.approval_id = Some(approval_id);
+
+        self.internal_invalidate_listing(&nft_contract_id, &token_id);
+
+        let approved = swap
+            .offered
+            .iter()
+            .chain(swap.wanted.iter())
+            .all(|token| token.approval_id.is_some());
+        if !approved {
+            self.swaps.insert(&args.swap_id.0, &swap);
+            MarketEvent::SwapUpdated(vec![swap]).emit();
+            return None;
+        }
+
+        self.assert_not_paused(MarketFeature::Purchasing);
+        self.internal_remove_swap(args.swap_id.0);
+        let current_account_id = env::current_account_id();
+        let transfers = swap
+            .offered
+            .iter()
+            .chain(swap.wanted.iter())
+            .map(|token| {
+                nft_sale::nft_transfer(
+                    current_account_id.clone(),
+                    token.token_id.clone(),
+                    token.approval_id,
+                    None,
+                    &token.nft_contract_id,
+                    1,
+                    GAS_FOR_NFT_TRANSFER,
+                )
+            })
+            .reduce(|transfers, transfer| transfers.and(transfer))
+            .unwrap();
+        Some(transfers.then(ext_self::resolve_swap(
+            swap,
+            &current_account_id,
+            NO_DEPOSIT,
+            GAS_FOR_RESOLVE_SWAP,
+        )))
+    }
+
+    pub(crate) fn internal_remove_swap(&mut self, swap_id: u64) -> Swap {
+        let swap = self.swaps.remove(&swap_id).expect("No swap");
+        self.internal_release_storage(&swap.proposer_id, &ListingId::Swap(swap_id));
+        remove_id_by_account_id(&mut self.swaps_by_proposer_id, &swap.proposer_id, swap_id);
+        remove_id_by_account_id(
+            &mut self.swaps_by_counterparty_id,
+            &swap.counterparty_id,
+            swap_id,
+        );
+        MarketEvent::SwapRemoved(vec![swap.clone()]).emit();
+        swap
+    }
+
+    fn internal_get_swaps(
+        &self,
+        swap_ids: Option<UnorderedSet<u64>>,
+        from_index: U64,
+        limit: U64,
+    ) -> Vec<Swap> {
+        let swap_ids = if let Some(swap_ids) = swap_ids {
+            swap_ids
+        } else {
+            return vec![];
+        };
+        let keys = swap_ids.as_vector();
+        let start = u64::from(from_index);
+        let end = min(start + u64::from(limit), swap_ids.len());
+        (start..end)
+            .filter_map(|i| self.swaps.get(&keys.get(i).unwrap()))
+            .collect()
+    }
+}
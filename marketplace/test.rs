@@ -0,0 +1,2072 @@
+// use the attribute below for unit tests
+use super::*;
+
+use near_sdk::MockedBlockchain;
+use near_sdk::{testing_env, PromiseResult, VMContext};
+
+fn marketplace() -> AccountId {
+    "market.testnet".to_string()
+}
+
+fn nft() -> AccountId {
+    "nft.testnet".to_string()
+}
+
+fn ft() -> AccountId {
+    "ft.testnet".to_string()
+}
+
+fn st() -> AccountId {
+    "st.testnet".to_string()
+}
+
+fn robert() -> AccountId {
+    "robert.testnet".to_string()
+}
+
+fn alice() -> AccountId {
+    "alice.testnet".to_string()
+}
+
+// part of writing unit tests is setting up a mock context
+// this is a useful list to peek at when wondering what's available in env::*
+fn get_context(predecessor_account_id: String, attached_deposit: Balance) -> VMContext {
+    VMContext {
+        current_account_id: marketplace(),
+        signer_account_id: predecessor_account_id.clone(),
+        signer_account_pk: vec![0, 1, 2],
+        predecessor_account_id,
+        input: vec![],
+        block_index: 0,
+        block_timestamp: 0,
+        account_balance: 10u128.pow(30),
+        account_locked_balance: 0,
+        // leaves room for the storage released when sales are removed
+        storage_usage: 10u64.pow(6),
+        attached_deposit,
+        prepaid_gas: 10u64.pow(18),
+        random_seed: vec![0, 1, 2],
+        is_view: false,
+        output_data_receivers: vec![],
+        epoch_height: 19,
+    }
+}
+
+fn valid(account_id: AccountId) -> ValidAccountId {
+    ValidAccountId::try_from(account_id).unwrap()
+}
+
+fn new_marketplace() -> Marketplace {
+    let mut market = Marketplace::new(
+        valid(marketplace()),
+        Some(vec![valid(ft())]),
+        Some(vec![StTokenId {
+            st_token_id: valid(st()),
+            st_symbol: "zee".to_string(),
+        }]),
+    );
+    market.add_nft_contract(valid(nft()), None, None);
+    market
+}
+
+fn list_token(market: &mut Marketplace, token_id: &str, msg: &str) {
+    testing_env!(get_context(robert(), STORAGE_PER_SALE * 2));
+    market.storage_deposit(None, None);
+    testing_env!(get_context(nft(), 0));
+    market.nft_on_approve(
+        token_id.to_string(),
+        valid(robert()),
+        U64(1),
+        msg.to_string(),
+    );
+}
+
+#[test]
+fn init_marketplace() {
+    testing_env!(get_context(marketplace(), 0));
+    let market = new_marketplace();
+    let ft_token_ids = market.supported_ft_token_ids();
+    assert_eq!(market.get_owner_id(), marketplace());
+    assert!(ft_token_ids.contains(&Currency::Near));
+    assert!(ft_token_ids.contains(&Currency::Ft(ft())));
+    assert!(ft_token_ids.contains(&Currency::St(st(), "zee".to_string())));
+}
+
+#[test]
+fn add_st_token_id() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    assert!(market.add_ft_or_st_token_ids(valid(st()), Some("abc".to_string())));
+    assert!(market
+        .supported_ft_token_ids()
+        .contains(&Currency::St(st(), "abc".to_string())));
+}
+
+#[test]
+fn st_transfer_call_bids_and_tops_up_escrow() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    list_token(
+        &mut market,
+        "1",
+        r#"{"sale_conditions":[{"ft_or_st_token_id":"st.testnet","st_symbol":"zee","price":"10"}],"token_type":null}"#,
+    );
+
+    register(&mut market, alice());
+    testing_env!(get_context(st(), 0));
+    let unused = market.st_on_transfer(
+        "zee".to_string(),
+        alice(),
+        U128(4),
+        r#"{"nft_contract_id":"nft.testnet","token_id":"1"}"#.to_string(),
+    );
+    assert!(matches!(unused, PromiseOrValue::Value(U128(0))));
+    let bids = market.get_bids(nft(), "1".to_string(), valid(st()), Some("zee".to_string()));
+    assert_eq!(bids[0].owner_id, alice());
+    assert_eq!(bids[0].price, U128(4));
+
+    market.st_on_transfer("zee".to_string(), alice(), U128(6), String::new());
+    assert_eq!(
+        market.get_escrow_balance(valid(alice()), valid(st()), Some("zee".to_string())),
+        U128(6)
+    );
+}
+
+#[test]
+#[should_panic(expected = "Token st.testnet||abc not supported by this market")]
+fn st_transfer_call_needs_supported_symbol() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    testing_env!(get_context(st(), 0));
+    market.st_on_transfer("abc".to_string(), alice(), U128(6), String::new());
+}
+
+#[test]
+#[should_panic(expected = "Owner's method")]
+fn set_owner_id_only_owner() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    testing_env!(get_context(alice(), 0));
+    market.set_owner_id(valid(alice()));
+}
+
+#[test]
+fn list_and_remove_sale() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    list_token(
+        &mut market,
+        "1",
+        r#"{"sale_conditions":[{"ft_or_st_token_id":"ft.testnet","price":"10"}],"token_type":"unique"}"#,
+    );
+    let sales = market.get_sales_by_owner_id(robert(), U64(0), U64(10));
+    assert_eq!(sales.len(), 1);
+    assert_eq!(
+        sales[0].conditions.get(&Currency::Ft(ft())),
+        Some(&U128(10))
+    );
+
+    testing_env!(get_context(robert(), 1));
+    market.remove_sale(valid(nft()), "1".to_string());
+    assert!(market
+        .get_sales_by_owner_id(robert(), U64(0), U64(10))
+        .is_empty());
+}
+
+/// storage deposit covering a few listings, bids or escrow balances
+fn register(market: &mut Marketplace, account_id: AccountId) {
+    testing_env!(get_context(account_id, STORAGE_PER_SALE * 2));
+    market.storage_deposit(None, None);
+}
+
+fn bid_in_ft(market: &mut Marketplace, bidder_id: AccountId, amount: u128) {
+    register(market, bidder_id.clone());
+    testing_env!(get_context(ft(), 0));
+    market.ft_on_transfer(
+        bidder_id,
+        U128(amount),
+        r#"{"nft_contract_id":"nft.testnet","token_id":"1"}"#.to_string(),
+    );
+}
+
+#[test]
+fn replaced_bid_is_credited_to_escrow() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    list_token(
+        &mut market,
+        "1",
+        r#"{"sale_conditions":[{"ft_or_st_token_id":"ft.testnet","price":"10"}],"token_type":null}"#,
+    );
+    bid_in_ft(&mut market, alice(), 3);
+    bid_in_ft(&mut market, alice(), 5);
+
+    let balance = market.get_escrow_balance(valid(alice()), valid(ft()), None);
+    assert_eq!(balance, U128(3));
+    assert_eq!(
+        market
+            .get_escrow_balances(valid(alice()))
+            .get(&Currency::Ft(ft())),
+        Some(&U128(3))
+    );
+}
+
+fn events(logs: Vec<String>) -> Vec<near_sdk::serde_json::Value> {
+    logs.iter()
+        .filter_map(|log| log.strip_prefix("EVENT_JSON:"))
+        .map(|event| near_sdk::serde_json::from_str(event).unwrap())
+        .collect()
+}
+
+#[test]
+fn bid_events_are_emitted() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    list_token(
+        &mut market,
+        "1",
+        r#"{"sale_conditions":[{"ft_or_st_token_id":"ft.testnet","price":"10"}],"token_type":null}"#,
+    );
+    let created = events(near_sdk::test_utils::get_logs());
+    assert_eq!(created[0]["standard"], "nft_marketplace");
+    assert_eq!(created[0]["event"], "sale_created");
+    assert_eq!(created[0]["data"][0]["conditions"]["ft.testnet"], "10");
+
+    bid_in_ft(&mut market, alice(), 3);
+    bid_in_ft(&mut market, alice(), 5);
+    let replaced = events(near_sdk::test_utils::get_logs());
+    assert_eq!(replaced[0]["event"], "bid_refunded");
+    assert_eq!(replaced[0]["data"][0]["price"], "3");
+    assert_eq!(replaced[1]["event"], "bid_placed");
+    assert_eq!(replaced[1]["data"][0]["bidder_id"], alice());
+}
+
+#[test]
+fn offer_is_funded_from_escrow() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    list_token(
+        &mut market,
+        "1",
+        r#"{"sale_conditions":[{"ft_or_st_token_id":"ft.testnet","price":"10"}],"token_type":null}"#,
+    );
+    register(&mut market, alice());
+    testing_env!(get_context(ft(), 0));
+    market.ft_on_transfer(alice(), U128(6), String::new());
+
+    testing_env!(get_context(alice(), 1));
+    market.offer(
+        valid(nft()),
+        "1".to_string(),
+        valid(ft()),
+        None,
+        U128(4),
+        None,
+    );
+    let bids = market.get_bids(nft(), "1".to_string(), valid(ft()), None);
+    assert_eq!(bids[0].price, U128(4));
+    assert_eq!(
+        market.get_escrow_balance(valid(alice()), valid(ft()), None),
+        U128(2)
+    );
+}
+
+#[test]
+#[should_panic(expected = "Not enough escrow balance")]
+fn offer_needs_escrow_balance() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    list_token(
+        &mut market,
+        "1",
+        r#"{"sale_conditions":[{"ft_or_st_token_id":"ft.testnet","price":"10"}],"token_type":null}"#,
+    );
+    register(&mut market, alice());
+    testing_env!(get_context(ft(), 0));
+    market.ft_on_transfer(alice(), U128(3), String::new());
+
+    testing_env!(get_context(alice(), 1));
+    market.offer(
+        valid(nft()),
+        "1".to_string(),
+        valid(ft()),
+        None,
+        U128(4),
+        None,
+    );
+}
+
+#[test]
+fn failed_transfer_call_purchase_returns_amount() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    testing_env!(
+        get_context(marketplace(), 0),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        vec![PromiseResult::Failed]
+    );
+    let unused = market.resolve_purchase(
+        sold_token("1"),
+        Currency::Ft(ft()),
+        alice(),
+        U128(10),
+        U128(0),
+        false,
+    );
+    assert_eq!(unused, U128(10));
+    assert_eq!(
+        market.get_escrow_balance(valid(alice()), valid(ft()), None),
+        U128(0)
+    );
+}
+
+#[test]
+fn failed_withdraw_is_credited_back() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    market.internal_escrow_deposit(&alice(), &Currency::Ft(ft()), 7);
+
+    testing_env!(get_context(alice(), 1));
+    market.withdraw(valid(ft()), None);
+    assert_eq!(
+        market.get_escrow_balance(valid(alice()), valid(ft()), None),
+        U128(0)
+    );
+
+    testing_env!(
+        get_context(marketplace(), 0),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        vec![PromiseResult::Failed]
+    );
+    assert_eq!(
+        market.resolve_withdraw(alice(), Currency::Ft(ft()), U128(7)),
+        U128(0)
+    );
+    assert_eq!(
+        market.get_escrow_balance(valid(alice()), valid(ft()), None),
+        U128(7)
+    );
+}
+
+#[test]
+fn expired_bid_is_pruned_to_escrow() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    list_token(
+        &mut market,
+        "1",
+        r#"{"sale_conditions":[{"ft_or_st_token_id":"ft.testnet","price":"10"}],"token_type":null}"#,
+    );
+    register(&mut market, alice());
+    testing_env!(get_context(ft(), 0));
+    market.ft_on_transfer(
+        alice(),
+        U128(3),
+        r#"{"nft_contract_id":"nft.testnet","token_id":"1","expires_at":"100"}"#.to_string(),
+    );
+
+    let mut context = get_context(robert(), 0);
+    context.block_timestamp = 100;
+    testing_env!(context);
+    assert_eq!(market.prune_expired_bids(valid(nft()), "1".to_string()), 1);
+    assert_eq!(
+        market.get_escrow_balance(valid(alice()), valid(ft()), None),
+        U128(3)
+    );
+}
+
+#[test]
+fn accept_offer_skips_expired_highest_bid() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    list_token(
+        &mut market,
+        "1",
+        r#"{"sale_conditions":[{"ft_or_st_token_id":"ft.testnet","price":"10"}],"token_type":null}"#,
+    );
+    bid_in_ft(&mut market, "carol.testnet".to_string(), 3);
+    register(&mut market, alice());
+    testing_env!(get_context(ft(), 0));
+    market.ft_on_transfer(
+        alice(),
+        U128(5),
+        r#"{"nft_contract_id":"nft.testnet","token_id":"1","expires_at":"100"}"#.to_string(),
+    );
+
+    let mut context = get_context(robert(), 0);
+    context.block_timestamp = 100;
+    testing_env!(context);
+    market.accept_offer(valid(nft()), "1".to_string(), valid(ft()), None, None);
+    assert_eq!(
+        market.get_escrow_balance(valid(alice()), valid(ft()), None),
+        U128(5)
+    );
+    let accepted = events(near_sdk::test_utils::get_logs());
+    assert!(accepted.iter().any(|event| event["event"] == "bid_accepted"
+        && event["data"][0]["bidder_id"] == "carol.testnet"));
+}
+
+#[test]
+#[should_panic(expected = "Must be bid owner")]
+fn cancel_bid_only_bid_owner() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    list_token(
+        &mut market,
+        "1",
+        r#"{"sale_conditions":[{"ft_or_st_token_id":"ft.testnet","price":"10"}],"token_type":null}"#,
+    );
+    bid_in_ft(&mut market, alice(), 3);
+
+    testing_env!(get_context(robert(), 1));
+    market.cancel_bid(valid(nft()), "1".to_string(), valid(ft()), None);
+}
+
+#[test]
+#[should_panic(expected = "Bid amount must be greater than 0")]
+fn zero_offer_does_not_buy_bids_only_sale() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    list_token(
+        &mut market,
+        "1",
+        r#"{"sale_conditions":[{"ft_or_st_token_id":"near"}],"token_type":null}"#,
+    );
+    testing_env!(get_context(alice(), 0));
+    market.offer(
+        valid(nft()),
+        "1".to_string(),
+        valid("near".to_string()),
+        None,
+        U128(0),
+        None,
+    );
+}
+
+#[test]
+fn bids_are_sorted_and_bounded() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    list_token(
+        &mut market,
+        "1",
+        r#"{"sale_conditions":[{"ft_or_st_token_id":"ft.testnet","price":"100"}],"token_type":null}"#,
+    );
+    for i in 1..=10 {
+        bid_in_ft(&mut market, format!("bidder{}.testnet", i), i);
+    }
+    bid_in_ft(&mut market, alice(), 5);
+
+    let bids = market.get_bids(nft(), "1".to_string(), valid(ft()), None);
+    assert_eq!(bids.len(), 10);
+    assert_eq!(bids[0].price, U128(10));
+    // same price as bidder5 but came later
+    assert_eq!(bids[5].owner_id, "bidder5.testnet".to_string());
+    assert_eq!(bids[6].owner_id, alice());
+    assert_eq!(
+        market.get_escrow_balance(valid("bidder1.testnet".to_string()), valid(ft()), None),
+        U128(1)
+    );
+}
+
+#[test]
+fn accept_offer_of_given_bidder() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    list_token(
+        &mut market,
+        "1",
+        r#"{"sale_conditions":[{"ft_or_st_token_id":"ft.testnet","price":"100"}],"token_type":null}"#,
+    );
+    bid_in_ft(&mut market, alice(), 3);
+    bid_in_ft(&mut market, "bob.testnet".to_string(), 5);
+
+    testing_env!(get_context(robert(), 0));
+    market.accept_offer(
+        valid(nft()),
+        "1".to_string(),
+        valid(ft()),
+        None,
+        Some(valid(alice())),
+    );
+    assert!(market.get_sale(format!("{}||1", nft())).is_none());
+}
+
+#[test]
+fn collection_offer_funded_from_escrow() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    register(&mut market, alice());
+    testing_env!(get_context(ft(), 0));
+    market.ft_on_transfer(alice(), U128(20), "".to_string());
+
+    testing_env!(get_context(alice(), 1));
+    let offer_id = market.make_collection_offer(
+        valid(nft()),
+        Some("subscription".to_string()),
+        valid(ft()),
+        None,
+        U128(15),
+    );
+    let made = events(near_sdk::test_utils::get_logs());
+    assert_eq!(made[0]["event"], "collection_offer_made");
+    assert_eq!(made[0]["data"][0]["price"], "15");
+    assert_eq!(
+        market.get_escrow_balance(valid(alice()), valid(ft()), None),
+        U128(5)
+    );
+    let offers =
+        market.get_collection_offers(nft(), Some("subscription".to_string()), U64(0), U64(10));
+    assert_eq!(offers.len(), 1);
+
+    market.cancel_collection_offer(offer_id);
+    let removed = events(near_sdk::test_utils::get_logs());
+    assert_eq!(removed.last().unwrap()["event"], "collection_offer_removed");
+    assert_eq!(
+        market.get_escrow_balance(valid(alice()), valid(ft()), None),
+        U128(20)
+    );
+    assert!(market.get_collection_offer(offer_id).is_none());
+}
+
+/// approves token "7" for a "subscription" collection offer, the NFT contract then returns
+/// the token from `nft_token` as `token`
+fn accept_collection_offer(market: &mut Marketplace, token: PromiseResult) -> U64 {
+    register(market, alice());
+    testing_env!(get_context(alice(), 15));
+    let offer_id = market.make_collection_offer(
+        valid(nft()),
+        Some("subscription".to_string()),
+        valid("near".to_string()),
+        None,
+        U128(15),
+    );
+
+    // a token_type in the msg is ignored, the one of the NFT contract counts
+    testing_env!(get_context(nft(), 0));
+    market.nft_on_approve(
+        "7".to_string(),
+        valid(robert()),
+        U64(2),
+        format!(
+            r#"{{"accept_collection_offer":"{}","token_type":"subscription"}}"#,
+            offer_id.0
+        ),
+    );
+    assert!(market.get_collection_offer(offer_id).is_some());
+
+    testing_env!(
+        get_context(marketplace(), 0),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        vec![token]
+    );
+    market.resolve_accept_collection_offer(offer_id, nft(), "7".to_string(), robert(), U64(2));
+    offer_id
+}
+
+#[test]
+fn accept_collection_offer_on_approve() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    let offer_id = accept_collection_offer(
+        &mut market,
+        nft_typed_token_result(robert(), 2, Some("subscription")),
+    );
+    assert!(market.get_collection_offer(offer_id).is_none());
+}
+
+#[test]
+#[should_panic(expected = "Offer is for another token type")]
+fn collection_offer_checks_token_type() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    accept_collection_offer(
+        &mut market,
+        nft_typed_token_result(robert(), 2, Some("unique")),
+    );
+}
+
+#[test]
+#[should_panic(expected = "Token is not approved by its owner")]
+fn collection_offer_checks_approval() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    accept_collection_offer(
+        &mut market,
+        nft_typed_token_result(alice(), 2, Some("subscription")),
+    );
+}
+
+#[test]
+fn fee_precedence() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    market.set_fee(100);
+    market.set_ft_or_st_fee(valid(ft()), None, Some(200));
+    market.set_nft_contract_fee(valid("other-nft.testnet".to_string()), Some(300));
+
+    assert_eq!(market.get_fee_bps(valid(nft()), valid(ft()), None), 200);
+    assert_eq!(
+        market.get_fee_bps(valid(nft()), valid(st()), Some("zee".to_string())),
+        100
+    );
+    assert_eq!(
+        market.get_fee_bps(valid("other-nft.testnet".to_string()), valid(ft()), None),
+        300
+    );
+}
+
+#[test]
+fn purchase_fee_goes_to_treasury() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    market.set_fee(250);
+
+    let mut payout: Payout = HashMap::new();
+    payout.insert(robert(), U128(900));
+    payout.insert(alice(), U128(75));
+    testing_env!(
+        get_context(marketplace(), 0),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        vec![PromiseResult::Successful(
+            near_sdk::serde_json::to_vec(&payout).unwrap()
+        )]
+    );
+    let unused = market.resolve_purchase(
+        sold_token("1"),
+        Currency::Ft(ft()),
+        alice(),
+        U128(1000),
+        U128(25),
+        false,
+    );
+    assert_eq!(unused, U128(0));
+    assert_eq!(
+        market.get_treasury_balances().get(&Currency::Ft(ft())),
+        Some(&U128(25))
+    );
+}
+
+fn sold_token(token_id: &str) -> SoldToken {
+    SoldToken {
+        nft_contract_id: nft(),
+        token_id: token_id.to_string(),
+        token_type: Some("content".to_string()),
+        seller_id: robert(),
+        custodial: false,
+    }
+}
+
+#[test]
+fn prune_expired_sales() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    list_token(
+        &mut market,
+        "1",
+        r#"{"sale_conditions":[{"ft_or_st_token_id":"ft.testnet","price":"10"}],"token_type":null,"expires_at":"100"}"#,
+    );
+    list_token(
+        &mut market,
+        "2",
+        r#"{"sale_conditions":[{"ft_or_st_token_id":"ft.testnet","price":"10"}],"token_type":null,"expires_at":"200"}"#,
+    );
+    bid_in_ft(&mut market, alice(), 3);
+
+    let mut context = get_context(alice(), 0);
+    context.block_timestamp = 150;
+    testing_env!(context);
+    assert_eq!(market.prune_expired_sales(10), 1);
+    let sales = market.get_sales_by_owner_id(robert(), U64(0), U64(10));
+    assert_eq!(sales.len(), 1);
+    assert_eq!(sales[0].token_id, "2".to_string());
+    assert_eq!(
+        market.get_escrow_balance(valid(alice()), valid(ft()), None),
+        U128(3)
+    );
+}
+
+#[test]
+fn prune_delisted_sales() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    list_token(
+        &mut market,
+        "1",
+        r#"{"sale_conditions":[{"ft_or_st_token_id":"ft.testnet","price":"10"}],"token_type":null}"#,
+    );
+    bid_in_ft(&mut market, alice(), 3);
+
+    testing_env!(get_context(marketplace(), 0));
+    market.remove_nft_contract(valid(nft()));
+    testing_env!(get_context(alice(), 0));
+    assert_eq!(market.prune_delisted_sales(valid(nft()), 10), 1);
+    assert!(market
+        .get_sales_by_owner_id(robert(), U64(0), U64(10))
+        .is_empty());
+    assert_eq!(
+        market.get_escrow_balance(valid(alice()), valid(ft()), None),
+        U128(3)
+    );
+}
+
+#[test]
+#[should_panic(expected = "NFT contract is still allowed on this market")]
+fn prune_delisted_sales_needs_removed_contract() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    market.prune_delisted_sales(valid(nft()), 10);
+}
+
+#[test]
+#[should_panic(expected = "Sale expired")]
+fn expired_sale_rejects_purchase() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    list_token(
+        &mut market,
+        "1",
+        r#"{"sale_conditions":[{"ft_or_st_token_id":"ft.testnet","price":"10"}],"token_type":null,"expires_at":"100"}"#,
+    );
+    let mut context = get_context(ft(), 0);
+    context.block_timestamp = 100;
+    testing_env!(context);
+    market.ft_on_transfer(
+        alice(),
+        U128(10),
+        r#"{"nft_contract_id":"nft.testnet","token_id":"1"}"#.to_string(),
+    );
+}
+
+fn bundle_of_two(market: &mut Marketplace) -> U64 {
+    testing_env!(get_context(robert(), STORAGE_PER_SALE));
+    market.storage_deposit(None, None);
+    testing_env!(get_context(robert(), 0));
+    let bundle_id = market.create_bundle(vec![Price {
+        ft_or_st_token_id: valid(ft()),
+        price: Some(U128(1000)),
+        st_symbol: None,
+    }]);
+    let msg = format!(
+        r#"{{"bundle_id":"{}","token_type":"content"}}"#,
+        bundle_id.0
+    );
+    testing_env!(get_context(nft(), 0));
+    for token_id in ["1", "2"].iter() {
+        market.nft_on_approve(token_id.to_string(), valid(robert()), U64(1), msg.clone());
+    }
+    bundle_id
+}
+
+#[test]
+fn bundle_payouts_are_merged() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    let bundle_id = bundle_of_two(&mut market);
+    let bundle = market.get_bundle(bundle_id).unwrap();
+    assert_eq!(bundle.tokens.len(), 2);
+
+    let payout: Payout = [(robert(), U128(500))].iter().cloned().collect();
+    let result = near_sdk::serde_json::to_vec(&payout).unwrap();
+    testing_env!(
+        get_context(marketplace(), 0),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        vec![
+            PromiseResult::Successful(result.clone()),
+            PromiseResult::Successful(result)
+        ]
+    );
+    let unused = market.resolve_bundle_purchase(
+        bundle,
+        Currency::Ft(ft()),
+        alice(),
+        U128(1000),
+        U128(0),
+        false,
+    );
+    assert_eq!(unused, U128(0));
+    let completed = events(near_sdk::test_utils::get_logs());
+    assert_eq!(completed[0]["event"], "purchase_completed");
+    assert_eq!(completed[0]["data"].as_array().unwrap().len(), 2);
+    assert_eq!(completed[0]["data"][1]["payout"]["robert.testnet"], "500");
+    let trades = market.get_token_trades(nft(), "2".to_string());
+    assert_eq!(trades[0].token_type, Some("content".to_string()));
+}
+
+#[test]
+fn bundle_royalties_above_policy_are_capped() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    market.add_nft_contract(
+        valid(nft()),
+        Some(NFTContractPolicy {
+            max_royalty_bps: Some(1000),
+        }),
+        None,
+    );
+    let bundle_id = bundle_of_two(&mut market);
+    let bundle = market.get_bundle(bundle_id).unwrap();
+
+    // 20% royalty on each token, above the 10% policy
+    let payout: Payout = [
+        (robert(), U128(400)),
+        ("artist.testnet".to_string(), U128(100)),
+    ]
+    .iter()
+    .cloned()
+    .collect();
+    let result = near_sdk::serde_json::to_vec(&payout).unwrap();
+    testing_env!(
+        get_context(marketplace(), 0),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        vec![
+            PromiseResult::Successful(result.clone()),
+            PromiseResult::Successful(result)
+        ]
+    );
+    market.resolve_bundle_purchase(
+        bundle,
+        Currency::Ft(ft()),
+        alice(),
+        U128(1000),
+        U128(0),
+        false,
+    );
+    let completed = events(near_sdk::test_utils::get_logs());
+    let completed = completed.last().unwrap();
+    assert_eq!(completed["event"], "purchase_completed");
+    assert_eq!(completed["data"][0]["payout"]["artist.testnet"], "50");
+    assert_eq!(completed["data"][0]["payout"]["robert.testnet"], "450");
+}
+
+#[test]
+fn trades_of_least_recently_traded_token_are_dropped() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    for i in 0..=MAX_TRADED_TOKENS {
+        testing_env!(get_context(marketplace(), 0));
+        market.internal_record_trade(Trade {
+            nft_contract_id: nft(),
+            token_id: i.to_string(),
+            token_type: None,
+            seller_id: robert(),
+            buyer_id: alice(),
+            ft_or_st: Currency::Ft(ft()),
+            price: U128(10),
+            timestamp: U64(i),
+        });
+    }
+    assert!(market.get_token_trades(nft(), "0".to_string()).is_empty());
+    assert_eq!(market.get_token_trades(nft(), "1".to_string()).len(), 1);
+    assert_eq!(market.traded_tokens.len(), MAX_TRADED_TOKENS);
+}
+
+#[test]
+fn undelivered_bundle_tokens_can_be_claimed() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    let bundle_id = bundle_of_two(&mut market);
+    let bundle = market.get_bundle(bundle_id).unwrap();
+    let tokens: Vec<HeldToken> = bundle
+        .tokens
+        .iter()
+        .map(|token| HeldToken {
+            nft_contract_id: token.nft_contract_id.clone(),
+            token_id: token.token_id.clone(),
+        })
+        .collect();
+
+    testing_env!(
+        get_context(marketplace(), 0),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        vec![PromiseResult::Successful(vec![]), PromiseResult::Failed]
+    );
+    let undelivered = market.resolve_delivery(alice(), tokens);
+    assert_eq!(undelivered.len(), 1);
+    assert_eq!(undelivered[0].token_id, "2");
+    assert_eq!(
+        near_sdk::test_utils::get_logs(),
+        vec!["Could not transfer nft.testnet||2 to alice.testnet".to_string()]
+    );
+    assert_eq!(
+        market.get_undelivered_tokens(valid(alice()))[0].token_id,
+        "2"
+    );
+
+    testing_env!(get_context(alice(), 0));
+    market.claim_tokens();
+    assert!(market.get_undelivered_tokens(valid(alice())).is_empty());
+}
+
+#[test]
+fn removed_bundle_revokes_approvals() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    let bundle_id = bundle_of_two(&mut market);
+    testing_env!(get_context(robert(), 1));
+    market.remove_bundle(bundle_id);
+    assert!(market.get_bundle(bundle_id).is_none());
+    assert_eq!(near_sdk::test_utils::get_created_receipts().len(), 2);
+}
+
+#[test]
+fn failed_bundle_transfer_refunds_buyer() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    let bundle_id = bundle_of_two(&mut market);
+    let bundle = market.get_bundle(bundle_id).unwrap();
+
+    let payout: Payout = [(robert(), U128(500))].iter().cloned().collect();
+    testing_env!(
+        get_context(marketplace(), 0),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        vec![
+            PromiseResult::Successful(near_sdk::serde_json::to_vec(&payout).unwrap()),
+            PromiseResult::Failed
+        ]
+    );
+    let unused = market.resolve_bundle_purchase(
+        bundle,
+        Currency::Ft(ft()),
+        alice(),
+        U128(1000),
+        U128(0),
+        true,
+    );
+    assert_eq!(unused, U128(0));
+    let failed = events(near_sdk::test_utils::get_logs());
+    assert_eq!(failed[0]["event"], "purchase_failed");
+    assert!(failed[0]["data"][0]["payout"].is_null());
+    assert_eq!(
+        market.get_escrow_balance(valid(alice()), valid(ft()), None),
+        U128(1000)
+    );
+}
+
+fn propose_swap_with_ft_top_up(market: &mut Marketplace) -> U64 {
+    testing_env!(get_context(robert(), STORAGE_PER_SALE));
+    market.storage_deposit(None, None);
+    testing_env!(get_context(ft(), 0));
+    market.ft_on_transfer(robert(), U128(50), "".to_string());
+    testing_env!(get_context(robert(), 1));
+    market.propose_swap(
+        valid(alice()),
+        vec![SwapTokenJson {
+            nft_contract_id: valid(nft()),
+            token_id: "pass".to_string(),
+        }],
+        vec![SwapTokenJson {
+            nft_contract_id: valid(nft()),
+            token_id: "unique".to_string(),
+        }],
+        Some(valid(ft())),
+        None,
+        Some(U128(50)),
+    )
+}
+
+#[test]
+fn swap_executes_once_both_sides_approved() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    let swap_id = propose_swap_with_ft_top_up(&mut market);
+    let msg = format!(r#"{{"swap_id":"{}","token_type":null}}"#, swap_id.0);
+
+    testing_env!(get_context(nft(), 0));
+    market.nft_on_approve("pass".to_string(), valid(robert()), U64(1), msg.clone());
+    let swap = market.get_swap(swap_id).unwrap();
+    assert_eq!(swap.offered[0].approval_id, Some(U64(1)));
+    assert_eq!(
+        market
+            .get_swaps_by_counterparty_id(alice(), U64(0), U64(10))
+            .len(),
+        1
+    );
+
+    market.nft_on_approve("unique".to_string(), valid(alice()), U64(2), msg);
+    let removed = events(near_sdk::test_utils::get_logs());
+    assert_eq!(removed.last().unwrap()["event"], "swap_removed");
+    assert!(market.get_swap(swap_id).is_none());
+    assert!(market
+        .get_swaps_by_proposer_id(robert(), U64(0), U64(10))
+        .is_empty());
+}
+
+#[test]
+fn failed_swap_refunds_top_up() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    let swap_id = propose_swap_with_ft_top_up(&mut market);
+    assert_eq!(
+        market.get_escrow_balance(valid(robert()), valid(ft()), None),
+        U128(0)
+    );
+    let swap = market.get_swap(swap_id).unwrap();
+
+    testing_env!(
+        get_context(marketplace(), 0),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        vec![PromiseResult::Successful(vec![]), PromiseResult::Failed]
+    );
+    market.resolve_swap(swap);
+    assert_eq!(
+        market.get_escrow_balance(valid(robert()), valid(ft()), None),
+        U128(50)
+    );
+}
+
+#[test]
+fn swap_top_up_is_credited_to_counterparty() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    let swap_id = propose_swap_with_ft_top_up(&mut market);
+    let swap = market.get_swap(swap_id).unwrap();
+
+    testing_env!(
+        get_context(marketplace(), 0),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        vec![
+            PromiseResult::Successful(vec![]),
+            PromiseResult::Successful(vec![])
+        ]
+    );
+    market.resolve_swap(swap);
+    let completed = events(near_sdk::test_utils::get_logs());
+    assert_eq!(completed[0]["event"], "swap_completed");
+    assert_eq!(
+        market.get_escrow_balance(valid(alice()), valid(ft()), None),
+        U128(50)
+    );
+    // each side gets its token with a `resolve_delivery` callback
+    assert_eq!(near_sdk::test_utils::get_created_receipts().len(), 4);
+    assert_eq!(
+        market.get_escrow_balance(valid(robert()), valid(ft()), None),
+        U128(0)
+    );
+}
+
+#[test]
+#[should_panic(expected = "Token is not part of the swap")]
+fn swap_rejects_other_tokens() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    let swap_id = propose_swap_with_ft_top_up(&mut market);
+    testing_env!(get_context(nft(), 0));
+    market.nft_on_approve(
+        "other".to_string(),
+        valid(alice()),
+        U64(1),
+        format!(r#"{{"swap_id":"{}"}}"#, swap_id.0),
+    );
+}
+
+#[test]
+#[should_panic(expected = "Sale is reserved for another buyer")]
+fn reserved_sale_rejects_other_buyers() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    list_token(
+        &mut market,
+        "1",
+        r#"{"sale_conditions":[{"ft_or_st_token_id":"ft.testnet","price":"10"}],"token_type":null,"reserved_for":["bob.testnet"]}"#,
+    );
+    let sales = market.get_sales_by_owner_id(robert(), U64(0), U64(10));
+    assert_eq!(sales[0].reserved_for, Some(vec!["bob.testnet".to_string()]));
+    bid_in_ft(&mut market, alice(), 3);
+}
+
+#[test]
+fn listing_storage_is_measured_and_released() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    list_token(
+        &mut market,
+        "1",
+        r#"{"sale_conditions":[{"ft_or_st_token_id":"ft.testnet","price":"10"}],"token_type":null}"#,
+    );
+    let used = market.storage_used(valid(robert())).0;
+    assert!(used > 0);
+    let balance = market.storage_balance_of(valid(robert())).unwrap();
+    let bounds = market.storage_balance_bounds();
+    assert_eq!(
+        balance.available.0,
+        balance.total.0 - bounds.min.0 - u128::from(used) * STORAGE_PRICE_PER_BYTE
+    );
+
+    // top-ups smaller than a sale are accepted once registered
+    testing_env!(get_context(robert(), 1));
+    market.storage_deposit(None, None);
+    market.storage_withdraw(None);
+    assert_eq!(
+        market
+            .storage_balance_of(valid(robert()))
+            .unwrap()
+            .available,
+        U128(0)
+    );
+
+    market.remove_sale(valid(nft()), "1".to_string());
+    assert_eq!(market.storage_used(valid(robert())), U64(0));
+    assert!(market.storage_unregister(None));
+    assert!(market.storage_balance_of(valid(robert())).is_none());
+}
+
+#[test]
+#[should_panic(expected = "The amount is greater than the available storage balance")]
+fn storage_of_listings_cannot_be_withdrawn() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    list_token(
+        &mut market,
+        "1",
+        r#"{"sale_conditions":[{"ft_or_st_token_id":"ft.testnet","price":"10"}],"token_type":null}"#,
+    );
+    let total = market.storage_balance_of(valid(robert())).unwrap().total;
+    testing_env!(get_context(robert(), 1));
+    market.storage_withdraw(Some(total));
+}
+
+#[test]
+fn forced_unregister_removes_listings() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    list_token(
+        &mut market,
+        "1",
+        r#"{"sale_conditions":[{"ft_or_st_token_id":"ft.testnet","price":"10"}],"token_type":null}"#,
+    );
+    bid_in_ft(&mut market, alice(), 3);
+    testing_env!(get_context(robert(), 1));
+    assert!(market.storage_unregister(Some(true)));
+    assert!(market
+        .get_sales_by_owner_id(robert(), U64(0), U64(10))
+        .is_empty());
+    assert_eq!(
+        market.get_escrow_balance(valid(alice()), valid(ft()), None),
+        U128(3)
+    );
+}
+
+#[test]
+#[should_panic(expected = "Not enough storage deposit")]
+fn escrow_top_up_needs_storage_deposit() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    testing_env!(get_context(ft(), 0));
+    market.ft_on_transfer(alice(), U128(1), String::new());
+}
+
+#[test]
+fn bids_and_escrow_are_charged_to_their_owner() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    list_token(
+        &mut market,
+        "1",
+        r#"{"sale_conditions":[{"ft_or_st_token_id":"ft.testnet","price":"10"}],"token_type":null}"#,
+    );
+    bid_in_ft(&mut market, alice(), 3);
+    let bid_storage = market.storage_used(valid(alice())).0;
+    assert!(bid_storage > 0);
+
+    // the refunded bid is released, its escrow balance is charged instead
+    testing_env!(get_context(alice(), 1));
+    market.cancel_bid(valid(nft()), "1".to_string(), valid(ft()), None);
+    assert!(market.storage_used(valid(alice())).0 > 0);
+    assert_ne!(market.storage_used(valid(alice())).0, bid_storage);
+
+    market.withdraw(valid(ft()), None);
+    assert_eq!(market.storage_used(valid(alice())), U64(0));
+}
+
+#[test]
+#[should_panic(
+    expected = "Can't unregister the account with bids, collection offers or escrow balances"
+)]
+fn unregister_needs_empty_escrow() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    register(&mut market, alice());
+    testing_env!(get_context(ft(), 0));
+    market.ft_on_transfer(alice(), U128(5), String::new());
+
+    testing_env!(get_context(alice(), 1));
+    market.storage_unregister(Some(true));
+}
+
+#[test]
+fn deprecated_currency_bids_are_refunded() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    list_token(
+        &mut market,
+        "1",
+        r#"{"sale_conditions":[{"ft_or_st_token_id":"ft.testnet","price":"10"},{"ft_or_st_token_id":"near","price":"10"}],"token_type":null}"#,
+    );
+    bid_in_ft(&mut market, alice(), 3);
+
+    testing_env!(get_context(marketplace(), 0));
+    assert!(market.remove_ft_or_st_token_id(valid(ft()), None));
+    assert!(!market
+        .supported_ft_token_ids()
+        .contains(&Currency::Ft(ft())));
+    assert_eq!(market.deprecated_ft_token_ids(), vec![Currency::Ft(ft())]);
+    let affected = market.get_sales_by_ft_or_st(valid(ft()), None, U64(0), U64(10));
+    assert_eq!(affected.len(), 1);
+
+    assert_eq!(
+        market.refund_deprecated_bids(valid(nft()), "1".to_string()),
+        1
+    );
+    assert_eq!(
+        market.get_escrow_balance(valid(alice()), valid(ft()), None),
+        U128(3)
+    );
+    testing_env!(get_context(robert(), 1));
+    market.remove_price(valid(nft()), "1".to_string(), valid(ft()), None);
+    assert!(market
+        .get_sales_by_ft_or_st(valid(ft()), None, U64(0), U64(10))
+        .is_empty());
+}
+
+#[test]
+#[should_panic(expected = "Token ft.testnet is deprecated")]
+fn deprecated_currency_rejects_bids() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    list_token(
+        &mut market,
+        "1",
+        r#"{"sale_conditions":[{"ft_or_st_token_id":"ft.testnet","price":"10"}],"token_type":null}"#,
+    );
+    testing_env!(get_context(marketplace(), 0));
+    market.remove_ft_or_st_token_id(valid(ft()), None);
+    bid_in_ft(&mut market, alice(), 3);
+}
+
+fn list_priced(market: &mut Marketplace, token_id: &str, price: u128, token_type: &str) {
+    list_token(
+        market,
+        token_id,
+        &format!(
+            r#"{{"sale_conditions":[{{"ft_or_st_token_id":"ft.testnet","price":"{}"}}],"token_type":"{}"}}"#,
+            price, token_type
+        ),
+    );
+}
+
+#[test]
+fn search_sales_by_price() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    list_priced(&mut market, "1", 30, "content");
+    list_priced(&mut market, "2", 10, "reward");
+    list_priced(&mut market, "3", 20, "content");
+    list_priced(&mut market, "4", 50, "content");
+
+    let token_ids = |sales: Vec<SaleJson>| -> Vec<TokenId> {
+        sales.into_iter().map(|sale| sale.token_id).collect()
+    };
+    let filter = SaleFilter {
+        ft_or_st_token_id: Some(valid(ft())),
+        max_price: Some(U128(30)),
+        sort: Some(SaleSort::PriceDesc),
+        ..Default::default()
+    };
+    assert_eq!(
+        token_ids(market.search_sales(filter, U64(0), U64(10))),
+        vec!["1", "3", "2"]
+    );
+    let filter = SaleFilter {
+        nft_contract_id: Some(nft()),
+        token_type: Some("content".to_string()),
+        ft_or_st_token_id: Some(valid(ft())),
+        min_price: Some(U128(20)),
+        sort: Some(SaleSort::PriceAsc),
+        ..Default::default()
+    };
+    assert_eq!(
+        token_ids(market.search_sales(filter, U64(1), U64(10))),
+        vec!["1", "4"]
+    );
+    // pages are taken from the price index before filtering
+    let filter = SaleFilter {
+        token_type: Some("content".to_string()),
+        ft_or_st_token_id: Some(valid(ft())),
+        sort: Some(SaleSort::PriceAsc),
+        ..Default::default()
+    };
+    assert_eq!(
+        token_ids(market.search_sales(filter, U64(0), U64(2))),
+        vec!["3"]
+    );
+}
+
+#[test]
+fn token_type_index_is_per_contract() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    list_priced(&mut market, "1", 30, "content");
+    list_priced(&mut market, "2", 10, "content");
+    assert_eq!(
+        market
+            .get_sales_by_nft_token_type(nft(), "content".to_string(), U64(0), U64(10))
+            .len(),
+        2
+    );
+    assert!(market
+        .get_sales_by_nft_token_type(ft(), "content".to_string(), U64(0), U64(10))
+        .is_empty());
+
+    testing_env!(get_context(robert(), 1));
+    market.remove_sale(valid(nft()), "1".to_string());
+    let sales = market.get_sales_by_nft_token_type(nft(), "content".to_string(), U64(0), U64(10));
+    assert_eq!(sales.len(), 1);
+    assert_eq!(sales[0].token_id, "2".to_string());
+    let filter = SaleFilter {
+        ft_or_st_token_id: Some(valid(ft())),
+        sort: Some(SaleSort::PriceAsc),
+        ..Default::default()
+    };
+    assert_eq!(market.search_sales(filter, U64(0), U64(10)).len(), 1);
+}
+
+#[test]
+fn purchases_are_recorded() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    list_priced(&mut market, "2", 40, "content");
+
+    for (token_id, price) in [("1", 100), ("1", 60)].iter() {
+        let payout: Payout = [(robert(), U128(*price))].iter().cloned().collect();
+        let mut context = get_context(marketplace(), 0);
+        context.block_timestamp = *price as u64;
+        testing_env!(
+            context,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Successful(
+                near_sdk::serde_json::to_vec(&payout).unwrap()
+            )]
+        );
+        market.resolve_purchase(
+            sold_token(token_id),
+            Currency::Ft(ft()),
+            alice(),
+            U128(*price),
+            U128(0),
+            false,
+        );
+    }
+
+    let trades = market.get_token_trades(nft(), "1".to_string());
+    assert_eq!(trades.len(), 2);
+    assert_eq!(trades[0].price, U128(60));
+    assert_eq!(trades[0].seller_id, robert());
+    assert_eq!(
+        market.get_collection_trades(nft(), U64(1), U64(10)).len(),
+        1
+    );
+
+    let stats =
+        market.get_collection_stats(nft(), Some("content".to_string()), Some(valid(ft())), None);
+    assert_eq!(stats.trades, U64(2));
+    assert_eq!(stats.volume.get(&Currency::Ft(ft())), Some(&U128(160)));
+    assert_eq!(stats.last_sale.unwrap().timestamp, U64(60));
+    assert_eq!(stats.floor_price, Some(U128(40)));
+    assert_eq!(
+        market
+            .get_collection_stats(nft(), Some("reward".to_string()), None, None)
+            .trades,
+        U64(0)
+    );
+}
+
+#[test]
+#[should_panic(expected = "NFT contract fake.testnet not allowed on this market")]
+fn listing_needs_allowed_nft_contract() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    assert!(market.is_nft_contract_allowed(nft()));
+    testing_env!(get_context("fake.testnet".to_string(), 0));
+    market.nft_on_approve(
+        "1".to_string(),
+        valid(robert()),
+        U64(1),
+        r#"{"sale_conditions":[{"ft_or_st_token_id":"ft.testnet","price":"10"}],"token_type":null}"#
+            .to_string(),
+    );
+}
+
+#[test]
+fn payout_above_royalty_policy_is_capped() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    market.add_nft_contract(
+        valid(nft()),
+        Some(NFTContractPolicy {
+            max_royalty_bps: Some(1000),
+        }),
+        Some(100),
+    );
+    let contracts = market.get_nft_contracts(U64(0), U64(10));
+    assert_eq!(contracts[0].fee_bps, Some(100));
+
+    // 20% royalty to alice, above the 10% policy
+    let payout: Payout = [(robert(), U128(800)), (alice(), U128(200))]
+        .iter()
+        .cloned()
+        .collect();
+    testing_env!(
+        get_context(marketplace(), 0),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        vec![PromiseResult::Successful(
+            near_sdk::serde_json::to_vec(&payout).unwrap()
+        )]
+    );
+    let unused = market.resolve_purchase(
+        sold_token("1"),
+        Currency::Ft(ft()),
+        alice(),
+        U128(1000),
+        U128(0),
+        true,
+    );
+    // the token moved to the buyer, so the purchase completes with the royalty cut to 10%
+    assert_eq!(unused, U128(0));
+    assert_eq!(
+        market.get_escrow_balance(valid(alice()), valid(ft()), None),
+        U128(0)
+    );
+    assert_eq!(market.get_token_trades(nft(), "1".to_string()).len(), 1);
+    let completed = events(near_sdk::test_utils::get_logs());
+    assert_eq!(completed[0]["event"], "purchase_completed");
+    assert_eq!(completed[0]["data"][0]["payout"]["alice.testnet"], "100");
+    assert_eq!(completed[0]["data"][0]["payout"]["robert.testnet"], "900");
+}
+
+#[test]
+fn invalid_payout_after_transfer_pays_seller() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    testing_env!(
+        get_context(marketplace(), 0),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        vec![PromiseResult::Successful(b"\"not a payout\"".to_vec())]
+    );
+    let unused = market.resolve_purchase(
+        sold_token("1"),
+        Currency::Ft(ft()),
+        alice(),
+        U128(1000),
+        U128(0),
+        true,
+    );
+    assert_eq!(unused, U128(0));
+    assert_eq!(
+        market.get_escrow_balance(valid(alice()), valid(ft()), None),
+        U128(0)
+    );
+    let completed = events(near_sdk::test_utils::get_logs());
+    assert_eq!(completed[0]["event"], "purchase_completed");
+    assert_eq!(completed[0]["data"][0]["payout"]["robert.testnet"], "1000");
+}
+
+#[test]
+fn quote_checks_payout_limits() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    market.set_fee(100);
+    list_token(
+        &mut market,
+        "1",
+        r#"{"sale_conditions":[{"ft_or_st_token_id":"ft.testnet","price":"1000"}],"token_type":null}"#,
+    );
+    let quote = |market: &Marketplace, nft_payout: Option<Payout>| {
+        market.quote_purchase(
+            valid(nft()),
+            "1".to_string(),
+            valid(ft()),
+            None,
+            U128(1000),
+            valid(alice()),
+            nft_payout,
+        )
+    };
+
+    let first = quote(&market, None);
+    assert_eq!(first.fee, U128(10));
+    assert_eq!(first.balance, U128(990));
+    assert_eq!(first.max_len_payout, MAX_LEN_SETTLEMENT_PAYOUT);
+    assert!(first.payout.is_none());
+    assert!(!first.within_limits);
+
+    let payout: Payout = [(robert(), U128(890)), (alice(), U128(100))]
+        .iter()
+        .cloned()
+        .collect();
+    let quoted = quote(&market, Some(payout.clone()));
+    assert!(quoted.within_limits);
+    assert_eq!(quoted.payout, Some(payout));
+
+    // the seller would be paid the whole balance
+    let too_long: Payout = (0..101)
+        .map(|i| (format!("royalty{}.testnet", i), U128(9)))
+        .collect();
+    let quoted = quote(&market, Some(too_long));
+    assert!(!quoted.within_limits);
+    assert_eq!(
+        quoted.payout,
+        Some([(robert(), U128(990))].iter().cloned().collect())
+    );
+}
+
+#[test]
+#[should_panic(expected = "Sale is reserved for another buyer")]
+fn quote_checks_reservation() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    list_token(
+        &mut market,
+        "1",
+        r#"{"sale_conditions":[{"ft_or_st_token_id":"ft.testnet","price":"1000"}],"token_type":null,"reserved_for":["bob.testnet"]}"#,
+    );
+    market.quote_purchase(
+        valid(nft()),
+        "1".to_string(),
+        valid(ft()),
+        None,
+        U128(1000),
+        valid(alice()),
+        None,
+    );
+}
+
+#[test]
+fn large_payout_is_settled_in_pages() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    let payout: Payout = (0..25)
+        .map(|i| (format!("royalty{}.testnet", i), U128(40)))
+        .collect();
+    testing_env!(
+        get_context(marketplace(), 0),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        vec![PromiseResult::Successful(
+            near_sdk::serde_json::to_vec(&payout).unwrap()
+        )]
+    );
+    market.resolve_purchase(
+        sold_token("1"),
+        Currency::Ft(ft()),
+        alice(),
+        U128(1000),
+        U128(0),
+        false,
+    );
+    assert_eq!(market.get_token_trades(nft(), "1".to_string()).len(), 1);
+
+    let settlements = market.get_settlements(U64(0), U64(10));
+    assert_eq!(settlements.len(), 1);
+    assert_eq!(settlements[0].payout.len(), 15);
+    assert_eq!(settlements[0].tokens, vec!["nft.testnet||1".to_string()]);
+
+    testing_env!(get_context(robert(), 0));
+    assert!(!market.continue_settlement(U64(0)));
+    assert_eq!(market.get_settlement(U64(0)).unwrap().payout.len(), 5);
+    assert!(market.continue_settlement(U64(0)));
+    assert!(market.get_settlement(U64(0)).is_none());
+}
+
+#[test]
+fn custodial_sale_is_listed_and_returned() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    testing_env!(get_context(robert(), STORAGE_PER_SALE));
+    market.storage_deposit(None, None);
+    testing_env!(get_context(nft(), 0));
+    let return_token = market.nft_on_transfer(
+        robert(),
+        robert(),
+        "1".to_string(),
+        r#"{"sale_conditions":[{"ft_or_st_token_id":"ft.testnet","price":"10"}],"token_type":null}"#
+            .to_string(),
+    );
+    assert!(!return_token);
+    let sales = market.get_sales_by_owner_id(robert(), U64(0), U64(10));
+    assert!(sales[0].custodial);
+
+    testing_env!(get_context(robert(), 1));
+    market.remove_sale(valid(nft()), "1".to_string());
+    assert!(market.get_sale(format!("{}||1", nft())).is_none());
+}
+
+#[test]
+fn custodial_payout_goes_to_seller() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    market.add_nft_contract(
+        valid(nft()),
+        Some(NFTContractPolicy {
+            max_royalty_bps: Some(1000),
+        }),
+        None,
+    );
+    // the NFT contract sees the marketplace as the owner
+    let payout: Payout = [(marketplace(), U128(900)), (alice(), U128(100))]
+        .iter()
+        .cloned()
+        .collect();
+    testing_env!(
+        get_context(marketplace(), 0),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        vec![PromiseResult::Successful(
+            near_sdk::serde_json::to_vec(&payout).unwrap()
+        )]
+    );
+    let sold = SoldToken {
+        custodial: true,
+        ..sold_token("1")
+    };
+    market.resolve_purchase(
+        sold,
+        Currency::Ft(ft()),
+        alice(),
+        U128(1000),
+        U128(0),
+        false,
+    );
+    let completed = events(near_sdk::test_utils::get_logs());
+    assert_eq!(completed[0]["event"], "purchase_completed");
+    assert_eq!(completed[0]["data"][0]["payout"]["robert.testnet"], "900");
+    assert!(completed[0]["data"][0]["payout"]
+        .get("marketplace.testnet")
+        .is_none());
+}
+
+fn nft_token_result(owner_id: AccountId, approval_id: u64) -> PromiseResult {
+    nft_typed_token_result(owner_id, approval_id, None)
+}
+
+fn nft_typed_token_result(
+    owner_id: AccountId,
+    approval_id: u64,
+    token_type: Option<&str>,
+) -> PromiseResult {
+    let token = near_sdk::serde_json::json!({
+        "token_id": "1",
+        "owner_id": owner_id,
+        "approved_account_ids": { marketplace(): U64(approval_id) },
+        "token_type": token_type,
+    });
+    PromiseResult::Successful(near_sdk::serde_json::to_vec(&token).unwrap())
+}
+
+#[test]
+fn verify_sales_skips_missing_sales() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    list_priced(&mut market, "1", 30, "content");
+    market.verify_sales(vec![
+        "nft.testnet||1".to_string(),
+        "nft.testnet||2".to_string(),
+    ]);
+    assert_eq!(
+        near_sdk::test_utils::get_logs().last(),
+        Some(&"No sale nft.testnet||2".to_string())
+    );
+}
+
+#[test]
+fn verify_sale_removes_stale_sale() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    list_priced(&mut market, "1", 30, "content");
+    bid_in_ft(&mut market, alice(), 3);
+
+    testing_env!(
+        get_context(marketplace(), 0),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        vec![nft_token_result(robert(), 1)]
+    );
+    assert!(!market.resolve_verify_sale(nft(), "1".to_string(), robert(), U64(1)));
+
+    // token was transferred outside the marketplace
+    testing_env!(
+        get_context(marketplace(), 0),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        vec![nft_token_result(alice(), 1)]
+    );
+    assert!(market.resolve_verify_sale(nft(), "1".to_string(), robert(), U64(1)));
+    assert!(market.get_sale(format!("{}||1", nft())).is_none());
+    assert_eq!(
+        market.get_escrow_balance(valid(alice()), valid(ft()), None),
+        U128(3)
+    );
+}
+
+fn offer_on_unlisted_token(market: &mut Marketplace) -> Option<U64> {
+    testing_env!(get_context(alice(), STORAGE_PER_SALE));
+    market.storage_deposit(None, None);
+    testing_env!(get_context(ft(), 0));
+    market.ft_on_transfer(alice(), U128(10), String::new());
+    testing_env!(get_context(alice(), 1));
+    market.make_offer(valid(nft()), "1".to_string(), valid(ft()), None, U128(10));
+    testing_env!(
+        get_context(marketplace(), 0),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        vec![nft_token_result(robert(), 1)]
+    );
+    market.resolve_make_offer(
+        alice(),
+        nft(),
+        "1".to_string(),
+        Currency::Ft(ft()),
+        U128(10),
+    )
+}
+
+#[test]
+fn offer_on_unlisted_token_is_accepted_on_approve() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    let offer_id = offer_on_unlisted_token(&mut market).unwrap();
+    assert_eq!(
+        market.get_escrow_balance(valid(alice()), valid(ft()), None),
+        U128(0)
+    );
+    let offers = market.get_offers_for_owner(robert(), U64(0), U64(10));
+    assert_eq!(offers[0].offer_id, offer_id);
+    assert_eq!(offers[0].owner_id, alice());
+    assert!(market.storage_used(valid(alice())).0 > 0);
+
+    testing_env!(get_context(nft(), 0));
+    market.nft_on_approve(
+        "1".to_string(),
+        valid(robert()),
+        U64(2),
+        r#"{"accept_offer":"0"}"#.to_string(),
+    );
+    assert!(market.get_offer(offer_id).is_some());
+    testing_env!(
+        get_context(marketplace(), 0),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        vec![nft_token_result(robert(), 2)]
+    );
+    market.resolve_accept_offer(offer_id, nft(), "1".to_string(), robert(), U64(2));
+    assert!(market.get_offer(offer_id).is_none());
+    assert!(market
+        .get_offers_for_owner(robert(), U64(0), U64(10))
+        .is_empty());
+    assert_eq!(market.storage_used(valid(alice())), U64(0));
+}
+
+#[test]
+fn offer_on_missing_token_keeps_funds_in_escrow() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    register(&mut market, alice());
+    testing_env!(get_context(ft(), 0));
+    market.ft_on_transfer(alice(), U128(10), String::new());
+    testing_env!(
+        get_context(marketplace(), 0),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        vec![PromiseResult::Successful(b"null".to_vec())]
+    );
+    assert!(market
+        .resolve_make_offer(
+            alice(),
+            nft(),
+            "1".to_string(),
+            Currency::Ft(ft()),
+            U128(10)
+        )
+        .is_none());
+    assert_eq!(
+        market.get_escrow_balance(valid(alice()), valid(ft()), None),
+        U128(10)
+    );
+}
+
+#[test]
+fn cancelled_offer_goes_to_escrow() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    let offer_id = offer_on_unlisted_token(&mut market).unwrap();
+    testing_env!(get_context(alice(), 1));
+    market.cancel_offer(offer_id);
+    assert_eq!(
+        market.get_escrow_balance(valid(alice()), valid(ft()), None),
+        U128(10)
+    );
+    assert!(market
+        .get_offers_by_bidder(alice(), U64(0), U64(10))
+        .is_empty());
+}
+
+fn guardian() -> AccountId {
+    "guardian.testnet".to_string()
+}
+
+fn paused_market(features: Vec<MarketFeature>) -> Marketplace {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    list_token(
+        &mut market,
+        "1",
+        r#"{"sale_conditions":[{"ft_or_st_token_id":"ft.testnet","price":"10"}],"token_type":null}"#,
+    );
+    bid_in_ft(&mut market, alice(), 3);
+    testing_env!(get_context(marketplace(), 0));
+    assert!(market.add_guardian(valid(guardian())));
+    testing_env!(get_context(guardian(), 0));
+    market.pause(features);
+    market
+}
+
+#[test]
+fn paused_bidding_keeps_refunds_working() {
+    let mut market = paused_market(vec![MarketFeature::Bidding]);
+    let paused = events(near_sdk::test_utils::get_logs());
+    assert_eq!(paused[0]["event"], "pause_updated");
+    assert_eq!(paused[0]["data"][0]["feature"], "bidding");
+    assert_eq!(paused[0]["data"][0]["account_id"], guardian());
+    let status = market.get_pause_status();
+    assert!(status.bidding && !status.listing && !status.purchasing);
+
+    testing_env!(get_context(alice(), 1));
+    market.cancel_bid(valid(nft()), "1".to_string(), valid(ft()), None);
+    market.withdraw(valid(ft()), None);
+    assert_eq!(
+        market.get_escrow_balance(valid(alice()), valid(ft()), None),
+        U128(0)
+    );
+
+    testing_env!(get_context(marketplace(), 0));
+    market.unpause(vec![MarketFeature::Bidding, MarketFeature::Listing]);
+    // only the flag that changed is reported
+    let resumed = events(near_sdk::test_utils::get_logs());
+    assert_eq!(resumed[0]["data"].as_array().unwrap().len(), 1);
+    bid_in_ft(&mut market, alice(), 4);
+}
+
+#[test]
+#[should_panic(expected = "Bidding is paused")]
+fn paused_bidding_rejects_bids() {
+    let mut market = paused_market(vec![MarketFeature::Bidding]);
+    bid_in_ft(&mut market, alice(), 4);
+}
+
+#[test]
+#[should_panic(expected = "Listing is paused")]
+fn paused_listing_rejects_sales() {
+    let mut market = paused_market(vec![MarketFeature::Listing]);
+    list_token(
+        &mut market,
+        "2",
+        r#"{"sale_conditions":[{"ft_or_st_token_id":"ft.testnet","price":"10"}],"token_type":null}"#,
+    );
+}
+
+#[test]
+#[should_panic(expected = "Purchasing is paused")]
+fn paused_purchasing_rejects_purchase() {
+    let mut market = paused_market(vec![MarketFeature::Purchasing]);
+    bid_in_ft(&mut market, alice(), 10);
+}
+
+#[test]
+#[should_panic(expected = "Owner's method")]
+fn guardian_cannot_unpause() {
+    let mut market = paused_market(vec![MarketFeature::Purchasing]);
+    market.unpause(vec![MarketFeature::Purchasing]);
+}
+
+#[test]
+fn currency_keys_round_trip() {
+    let st_zee = Currency::new(&st(), Some("zee".to_string()));
+    assert_eq!(st_zee, Currency::St(st(), "zee".to_string()));
+    assert_eq!(Currency::from_key("st.testnet||zee"), st_zee);
+    assert_eq!(Currency::from_key("near"), Currency::Near);
+    assert_eq!(Currency::from_key(&ft()), Currency::Ft(ft()));
+    assert_eq!(
+        near_sdk::serde_json::to_string(&st_zee).unwrap(),
+        r#""st.testnet||zee""#
+    );
+}
+
+#[test]
+fn update_price_in_st_keeps_symbol() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut market = new_marketplace();
+    list_token(
+        &mut market,
+        "1",
+        r#"{"sale_conditions":[{"ft_or_st_token_id":"ft.testnet","price":"10"}],"token_type":null}"#,
+    );
+
+    testing_env!(get_context(robert(), 1));
+    market.update_price(
+        valid(nft()),
+        "1".to_string(),
+        valid(st()),
+        Some("zee".to_string()),
+        U128(5),
+    );
+    let sale = market.get_sale("nft.testnet||1".to_string()).unwrap();
+    assert_eq!(
+        sale.conditions.get(&Currency::St(st(), "zee".to_string())),
+        Some(&U128(5))
+    );
+    assert!(!sale.conditions.contains_key(&Currency::Ft(st())));
+}
+
+/// state of the first deployed marketplace with two sales, see `migrate`
+fn write_baseline_state() {
+    testing_env!(get_context(marketplace(), 0));
+    let mut ft_token_ids: UnorderedSet<String> =
+        UnorderedSet::new(StorageKey::FTTokenIds.try_to_vec().unwrap());
+    ft_token_ids.insert(&"near".to_string());
+    ft_token_ids.insert(&ft());
+    ft_token_ids.insert(&"st.testnet||zee".to_string());
+
+    // sales as stored by the first deployed marketplace, one bid per currency
+    let mut sales: UnorderedMap<ContractAndTokenId, Sale> =
+        UnorderedMap::new(StorageKey::Sales.try_to_vec().unwrap());
+    let mut by_token_type: UnorderedSet<ContractAndTokenId> = UnorderedSet::new(
+        StorageKey::ByNFTTokenTypeInner {
+            token_type_hash: hash_account_id(&"unique".to_string()),
+        }
+        .try_to_vec()
+        .unwrap(),
+    );
+    for token_id in &["1", "2"] {
+        let contract_and_token_id = format!("nft.testnet||{}", token_id);
+        let conditions: HashMap<String, U128> = vec![
+            ("near".to_string(), U128(3)),
+            ("st.testnet||zee".to_string(), U128(10)),
+        ]
+        .into_iter()
+        .collect();
+        let mut bids: HashMap<String, (AccountId, U128)> = HashMap::new();
+        bids.insert("st.testnet||zee".to_string(), (alice(), U128(4)));
+        let old_sale = (
+            robert(),
+            U64(1),
+            Some("unique".to_string()),
+            conditions,
+            bids,
+        );
+        sales.insert_raw(
+            &contract_and_token_id.try_to_vec().unwrap(),
+            &old_sale.try_to_vec().unwrap(),
+        );
+        by_token_type.insert(&contract_and_token_id);
+    }
+    let mut by_nft_token_type: LookupMap<String, UnorderedSet<ContractAndTokenId>> =
+        LookupMap::new(StorageKey::ByNFTTokenType.try_to_vec().unwrap());
+    by_nft_token_type.insert(&"unique".to_string(), &by_token_type);
+    let mut storage_deposits: LookupMap<AccountId, Balance> =
+        LookupMap::new(StorageKey::StorageDeposits.try_to_vec().unwrap());
+    storage_deposits.insert(&robert(), &(STORAGE_PER_SALE * 2));
+    env::state_write(&(
+        marketplace(),
+        sales,
+        LookupMap::<AccountId, UnorderedSet<ContractAndTokenId>>::new(
+            StorageKey::ByOwnerId.try_to_vec().unwrap(),
+        ),
+        LookupMap::<AccountId, UnorderedSet<TokenId>>::new(
+            StorageKey::ByNFTContractId.try_to_vec().unwrap(),
+        ),
+        by_nft_token_type,
+        ft_token_ids,
+        storage_deposits,
+    ));
+}
+
+#[test]
+fn migrate_from_baseline_layout_in_pages() {
+    write_baseline_state();
+    let mut market = Marketplace::migrate();
+    assert!(market.get_pause_status().listing);
+    let ft_token_ids = market.supported_ft_token_ids();
+    assert_eq!(ft_token_ids.len(), 3);
+    assert!(ft_token_ids.contains(&Currency::St(st(), "zee".to_string())));
+
+    assert_eq!(market.migrate_sales(1), U64(1));
+    assert_eq!(market.migrate_sales(10), U64(0));
+    market.unpause(vec![MarketFeature::Listing]);
+    assert!(!market.get_pause_status().listing);
+    let sale = market.get_sale("nft.testnet||1".to_string()).unwrap();
+    let st_zee = Currency::St(st(), "zee".to_string());
+    assert_eq!(sale.conditions.get(&Currency::Near), Some(&U128(3)));
+    assert_eq!(sale.conditions.get(&st_zee), Some(&U128(10)));
+    assert_eq!(sale.bids[&st_zee][0].owner_id, alice());
+    assert_eq!(sale.bids[&st_zee][0].expires_at, None);
+    assert!(!sale.custodial);
+    assert_eq!(
+        market
+            .get_sales_by_nft_token_type(nft(), "unique".to_string(), U64(0), U64(10))
+            .len(),
+        2
+    );
+    assert!(market
+        .by_nft_token_type
+        .get(&"unique".to_string())
+        .is_none());
+    assert_eq!(
+        market
+            .get_collection_stats(nft(), None, Some(valid(st())), Some("zee".to_string()))
+            .floor_price,
+        Some(U128(10))
+    );
+    assert_eq!(market.storage_used(valid(robert())), U64(2000));
+}
+
+#[test]
+#[should_panic(expected = "Sales are still being migrated, call migrate_sales first")]
+fn unpause_waits_for_migrated_sales() {
+    write_baseline_state();
+    let mut market = Marketplace::migrate();
+    market.migrate_sales(1);
+    market.unpause(vec![MarketFeature::Purchasing]);
+}
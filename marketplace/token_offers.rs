@@ -0,0 +1,289 @@
+use crate::*;
+
+const GAS_FOR_RESOLVE_MAKE_OFFER: Gas = 30_000_000_000_000;
+
+/// offer for a token whether it is listed or not, funds are held by the marketplace until
+/// the offer is accepted or cancelled. `token_owner_id` owned the token when the offer was made
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TokenOffer {
+    pub offer_id: U64,
+    pub owner_id: AccountId,
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+    pub token_owner_id: AccountId,
+    pub ft_or_st: Currency,
+    pub price: U128,
+}
+
+/// `nft_approve` msg accepting an offer, the token type is read from the NFT contract
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AcceptOfferArgs {
+    pub accept_offer: U64,
+}
+
+#[near_bindgen]
+impl Marketplace {
+    /// offer `price` for a token of an allowlisted NFT contract. NEAR offers are paid with the
+    /// attached deposit, FT and ST offers from the caller's escrow. The storage of the offer is
+    /// charged to the caller. Resolves to the offer id, or `None` if the token doesn't exist,
+    /// a NEAR deposit is then left in the caller's escrow
+    #[payable]
+    pub fn make_offer(
+        &mut self,
+        nft_contract_id: ValidAccountId,
+        token_id: TokenId,
+        ft_or_st_token_id: ValidAccountId,
+        st_symbol: Option<String>,
+        price: U128,
+    ) -> Promise {
+        self.assert_not_paused(MarketFeature::Bidding);
+        let owner_id = env::predecessor_account_id();
+        self.assert_nft_contract_allowed(nft_contract_id.as_ref());
+        let ft_or_st = Currency::new(ft_or_st_token_id.as_ref(), st_symbol);
+        if !self.ft_token_ids.contains(&ft_or_st) {
+            env::panic(format!("Token {} not supported by this market", ft_or_st).as_bytes());
+        }
+        assert!(price.0 > 0, "Price must be greater than 0");
+        // kept in escrow until the offer is recorded, so a failed callback can't lose it
+        self.internal_take_payment(&owner_id, &ft_or_st, price.0, "price");
+        self.internal_escrow_deposit(&owner_id, &ft_or_st, price.0);
+        self.internal_assert_storage_balance(&owner_id);
+
+        nft_sale::nft_token(
+            token_id.clone(),
+            nft_contract_id.as_ref(),
+            NO_DEPOSIT,
+            GAS_FOR_NFT_TOKEN,
+        )
+        .then(ext_self::resolve_make_offer(
+            owner_id,
+            nft_contract_id.into(),
+            token_id,
+            ft_or_st,
+            price,
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_RESOLVE_MAKE_OFFER,
+        ))
+    }
+
+    /// self callback, records the offer for the current owner of the token
+    #[private]
+    pub fn resolve_make_offer(
+        &mut self,
+        owner_id: AccountId,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        ft_or_st: Currency,
+        price: U128,
+    ) -> Option<U64> {
+        let token_owner_id = if let Some(token) = promise_nft_token() {
+            token.owner_id
+        } else {
+            env::log(format!("No token {} on {}", token_id, nft_contract_id).as_bytes());
+            return None;
+        };
+        assert_ne!(
+            token_owner_id, owner_id,
+            "Cannot make an offer on your own token"
+        );
+
+        self.internal_escrow_withdraw(&owner_id, &ft_or_st, price.0);
+        let initial_storage_usage = env::storage_usage();
+        let offer_id = self.next_token_offer_id;
+        self.next_token_offer_id += 1;
+        add_id_by_account_id(
+            &mut self.token_offers_by_owner_id,
+            &token_owner_id,
+            offer_id,
+            |account_id_hash| StorageKey::TokenOffersByOwnerIdInner { account_id_hash },
+        );
+        add_id_by_account_id(
+            &mut self.token_offers_by_bidder_id,
+            &owner_id,
+            offer_id,
+            |account_id_hash| StorageKey::TokenOffersByBidderIdInner { account_id_hash },
+        );
+        let offer = TokenOffer {
+            offer_id: U64(offer_id),
+            owner_id: owner_id.clone(),
+            nft_contract_id,
+            token_id,
+            token_owner_id,
+            ft_or_st,
+            price,
+        };
+        self.token_offers.insert(&offer_id, &offer);
+        self.internal_charge_storage(
+            &owner_id,
+            &ListingId::TokenOffer(offer_id),
+            initial_storage_usage,
+        );
+        MarketEvent::OfferMade(vec![offer]).emit();
+        Some(U64(offer_id))
+    }
+
+    /// self callback, fills the offer once the NFT contract confirmed the approval and
+    /// the token type
+    #[private]
+    pub fn resolve_accept_offer(
+        &mut self,
+        offer_id: U64,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        owner_id: AccountId,
+        approval_id: U64,
+    ) -> Promise {
+        let token_type = promise_approved_token_type(&owner_id, approval_id);
+        let offer = self.internal_remove_token_offer(offer_id.0);
+        MarketEvent::OfferAccepted(vec![OfferAcceptedData {
+            offer_id,
+            nft_contract_id: nft_contract_id.clone(),
+            token_id: token_id.clone(),
+            seller_id: owner_id.clone(),
+        }])
+        .emit();
+
+        self.internal_fill_offer(
+            SoldToken {
+                nft_contract_id,
+                token_id,
+                token_type,
+                seller_id: owner_id,
+                custodial: false,
+            },
+            approval_id,
+            offer.ft_or_st,
+            offer.owner_id,
+            offer.price,
+        )
+    }
+
+    /// offer owner takes back the offer, funds go to their escrow
+    #[payable]
+    pub fn cancel_offer(&mut self, offer_id: U64) {
+        assert_one_yocto();
+        let offer = self.internal_remove_token_offer(offer_id.0);
+        assert_eq!(
+            env::predecessor_account_id(),
+            offer.owner_id,
+            "Must be offer owner"
+        );
+        self.internal_escrow_deposit(&offer.owner_id, &offer.ft_or_st, offer.price.0);
+    }
+
+    /// views
+    pub fn get_offer(&self, offer_id: U64) -> Option<TokenOffer> {
+        self.token_offers.get(&offer_id.0)
+    }
+
+    /// offers made on tokens while the account owned them. The marketplace doesn't see
+    /// transfers, so offers stay listed for the owner at offer time: a new owner doesn't find
+    /// them here, while `internal_accept_offer` lets whoever owns the token accept
+    pub fn get_offers_for_owner(
+        &self,
+        account_id: AccountId,
+        from_index: U64,
+        limit: U64,
+    ) -> Vec<TokenOffer> {
+        self.internal_token_offers(
+            &self.token_offers_by_owner_id,
+            &account_id,
+            from_index,
+            limit,
+        )
+    }
+
+    pub fn get_offers_by_bidder(
+        &self,
+        account_id: AccountId,
+        from_index: U64,
+        limit: U64,
+    ) -> Vec<TokenOffer> {
+        self.internal_token_offers(
+            &self.token_offers_by_bidder_id,
+            &account_id,
+            from_index,
+            limit,
+        )
+    }
+}
+
+impl Marketplace {
+    /// called from `nft_on_approve`, the owner of `token_id` approved the marketplace to fill
+    /// the offer. The offer is filled by `resolve_accept_offer` after reading the token
+    pub(crate) fn internal_accept_offer(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        owner_id: AccountId,
+        approval_id: U64,
+        args: AcceptOfferArgs,
+    ) -> Promise {
+        let offer = self
+            .token_offers
+            .get(&args.accept_offer.0)
+            .expect("No offer");
+        assert!(
+            offer.nft_contract_id == nft_contract_id && offer.token_id == token_id,
+            "Offer is for another token"
+        );
+        assert_ne!(offer.owner_id, owner_id, "Cannot accept your own offer");
+
+        nft_sale::nft_token(
+            token_id.clone(),
+            &nft_contract_id,
+            NO_DEPOSIT,
+            GAS_FOR_NFT_TOKEN,
+        )
+        .then(ext_self::resolve_accept_offer(
+            args.accept_offer,
+            nft_contract_id,
+            token_id,
+            owner_id,
+            approval_id,
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_RESOLVE_ACCEPT_OFFER,
+        ))
+    }
+
+    pub(crate) fn internal_remove_token_offer(&mut self, offer_id: u64) -> TokenOffer {
+        let offer = self.token_offers.remove(&offer_id).expect("No offer");
+        self.internal_release_storage(&offer.owner_id, &ListingId::TokenOffer(offer_id));
+        remove_id_by_account_id(
+            &mut self.token_offers_by_owner_id,
+            &offer.token_owner_id,
+            offer_id,
+        );
+        remove_id_by_account_id(
+            &mut self.token_offers_by_bidder_id,
+            &offer.owner_id,
+            offer_id,
+        );
+        MarketEvent::OfferRemoved(vec![offer.clone()]).emit();
+        offer
+    }
+
+    fn internal_token_offers(
+        &self,
+        offers_by_account_id: &LookupMap<AccountId, UnorderedSet<u64>>,
+        account_id: &AccountId,
+        from_index: U64,
+        limit: U64,
+    ) -> Vec<TokenOffer> {
+        let offer_ids = if let Some(offer_ids) = offers_by_account_id.get(account_id) {
+            offer_ids
+        } else {
+            return vec![];
+        };
+        let keys = offer_ids.as_vector();
+        let start = u64::from(from_index);
+        let end = min(start + u64::from(limit), keys.len());
+        (start..end)
+            .map(|i| self.token_offers.get(&keys.get(i).unwrap()).unwrap())
+            .collect()
+    }
+}
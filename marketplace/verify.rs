@@ -0,0 +1,151 @@
+use crate::*;
+use near_sdk::PromiseResult;
+
+/// sales checked by one `verify_sales` call, 8 * (10 + 15) Tgas leaves room for the call itself
+const MAX_VERIFY_SALES: usize = 8;
+pub(crate) const GAS_FOR_NFT_TOKEN: Gas = 10_000_000_000_000;
+const GAS_FOR_RESOLVE_VERIFY_SALE: Gas = 15_000_000_000_000;
+
+/// fields of the NFT contract's `nft_token` needed to verify a sale
+#[derive(Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct NFTToken {
+    pub owner_id: AccountId,
+    pub approved_account_ids: HashMap<AccountId, U64>,
+    #[serde(default)]
+    pub token_type: TokenType,
+}
+
+/// token returned by the `nft_token` call the current callback resolves, `None` if the call
+/// failed or the token doesn't exist
+pub(crate) fn promise_nft_token() -> Option<NFTToken> {
+    match env::promise_result(0) {
+        PromiseResult::Successful(value) => {
+            near_sdk::serde_json::from_slice::<Option<NFTToken>>(&value)
+                .ok()
+                .flatten()
+        }
+        _ => None,
+    }
+}
+
+/// type of the token returned by `nft_token`, which `owner_id` must still own with the
+/// marketplace approved under `approval_id`
+pub(crate) fn promise_approved_token_type(owner_id: &AccountId, approval_id: U64) -> TokenType {
+    let token = promise_nft_token().expect("No token");
+    assert!(
+        &token.owner_id == owner_id
+            && token.approved_account_ids.get(&env::current_account_id()) == Some(&approval_id),
+        "Token is not approved by its owner"
+    );
+    token.token_type
+}
+
+#[near_bindgen]
+impl Marketplace {
+    /// anyone can check the sale against the NFT contract, a stale sale is removed and its
+    /// bids go to escrow. Resolves to `true` if the sale was stale
+    pub fn verify_sale(&mut self, nft_contract_id: ValidAccountId, token_id: TokenId) -> Promise {
+        self.internal_verify_sale(format!("{}{}{}", nft_contract_id, DELIMETER, token_id))
+            .expect("No sale")
+    }
+
+    /// batched `verify_sale` for keepers, e.g. with ids from `search_sales`. Sales removed in
+    /// the meantime are skipped
+    pub fn verify_sales(&mut self, contract_and_token_ids: Vec<ContractAndTokenId>) {
+        assert!(
+            contract_and_token_ids.len() <= MAX_VERIFY_SALES,
+            "Cannot verify more than {} sales at once",
+            MAX_VERIFY_SALES
+        );
+        for contract_and_token_id in contract_and_token_ids {
+            if self
+                .internal_verify_sale(contract_and_token_id.clone())
+                .is_none()
+            {
+                env::log(format!("No sale {}", contract_and_token_id).as_bytes());
+            }
+        }
+    }
+
+    /// self callback, the sale is stale if the token is gone, changed owner or
+    /// the marketplace approval was revoked or replaced
+    #[private]
+    pub fn resolve_verify_sale(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        owner_id: AccountId,
+        approval_id: U64,
+    ) -> bool {
+        let token = match env::promise_result(0) {
+            PromiseResult::Successful(value) => {
+                match near_sdk::serde_json::from_slice::<Option<NFTToken>>(&value) {
+                    Ok(token) => token,
+                    Err(_) => {
+                        env::log(b"Could not verify sale, invalid nft_token");
+                        return false;
+                    }
+                }
+            }
+            _ => {
+                env::log(b"Could not verify sale, nft_token failed");
+                return false;
+            }
+        };
+        // custodial sales are valid as long as the marketplace holds the token
+        let valid = token
+            .map(|token| {
+                token.owner_id == env::current_account_id()
+                    || (token.owner_id == owner_id
+                        && token.approved_account_ids.get(&env::current_account_id())
+                            == Some(&approval_id))
+            })
+            .unwrap_or(false);
+        if valid {
+            return false;
+        }
+
+        // sale could have been removed or listed again while the NFT contract was queried
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let listed_again = self
+            .sales
+            .get(&contract_and_token_id)
+            .map(|sale| sale.owner_id != owner_id || sale.approval_id != approval_id)
+            .unwrap_or(true);
+        if listed_again {
+            return false;
+        }
+        env::log(format!("Removing stale sale {}", contract_and_token_id).as_bytes());
+        let sale = self.internal_remove_sale(nft_contract_id, token_id);
+        self.refund_bids(&contract_and_token_id, &sale.bids);
+        true
+    }
+}
+
+impl Marketplace {
+    /// `None` if there is no sale
+    fn internal_verify_sale(
+        &mut self,
+        contract_and_token_id: ContractAndTokenId,
+    ) -> Option<Promise> {
+        let sale = self.sales.get(&contract_and_token_id)?;
+        let (nft_contract_id, token_id) = split_contract_and_token_id(&contract_and_token_id);
+        let promise = nft_sale::nft_token(
+            token_id.clone(),
+            &nft_contract_id,
+            NO_DEPOSIT,
+            GAS_FOR_NFT_TOKEN,
+        )
+        .then(ext_self::resolve_verify_sale(
+            nft_contract_id,
+            token_id,
+            sale.owner_id,
+            sale.approval_id,
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_RESOLVE_VERIFY_SALE,
+        ));
+        Some(promise)
+    }
+}
@@ -71,6 +71,43 @@ pub(crate) fn refund_approved_account_ids(
 }
 
 impl NonFungibleToken {
+    /// adds in contract_royalty and computes the owner's payout from the remainder
+    pub(crate) fn internal_payout(
+        &self,
+        owner_id: &AccountId,
+        token_id: &TokenId,
+        balance: Balance,
+    ) -> Payout {
+        let royalty = self.tokens_by_id.get(token_id).expect("No token").royalty;
+        let mut total_perpetual = 0;
+        let mut payout: Payout = HashMap::new();
+        for (k, v) in royalty.iter() {
+            if k != owner_id {
+                payout.insert(k.clone(), royalty_to_payout(*v, balance));
+                total_perpetual += *v;
+            }
+        }
+        // payout to contract owner - may be previous token owner -> then they get remainder of balance
+        if self.contract_royalty > 0 && &self.owner_id != owner_id {
+            payout.insert(
+                self.owner_id.clone(),
+                royalty_to_payout(self.contract_royalty, balance),
+            );
+            total_perpetual += self.contract_royalty;
+        }
+        // assert!(
+        //     total_perpetual <= MINTER_ROYALTY_CAP + CONTRACT_ROYALTY_CAP,
+        //     "Royalties should not be more than caps"
+        // );
+        // payout to previous owner
+        payout.insert(
+            owner_id.clone(),
+            royalty_to_payout(10000 - total_perpetual, balance),
+        );
+        env::log(format!("total_perpetual {:?}", total_perpetual).as_bytes());
+        payout
+    }
+
     pub(crate) fn assert_owner(&self) {
         assert_eq!(
             &env::predecessor_account_id(),
@@ -25,6 +25,9 @@ pub trait NonFungibleTokenCore {
         balance: Option<U128>,
     ) -> Option<Payout>;
 
+    /// NEP-199 view of the payout `nft_transfer_payout` would return for `balance`
+    fn nft_payout(&self, token_id: TokenId, balance: U128, max_len_payout: u32) -> Payout;
+
     /// Returns `true` if the token was transferred from the sender's account.
     fn nft_transfer_call(
         &mut self,
@@ -146,46 +149,24 @@ impl NonFungibleTokenCore for NonFungibleToken {
         );
 
         // compute payouts based on balance option
-        // adds in contract_royalty and computes previous owner royalty from remainder
-        let owner_id = previous_token.owner_id;
-        let royalty = self.tokens_by_id.get(&token_id).expect("No token").royalty;
-        let mut total_perpetual = 0;
-        let payout = if let Some(balance) = balance {
-            let balance_u128 = u128::from(balance);
-            let mut payout: Payout = HashMap::new();
-            for (k, v) in royalty.iter() {
-                let key = k.clone();
-                if key != owner_id {
-                    payout.insert(key, royalty_to_payout(*v, balance_u128));
-                    total_perpetual += *v;
-                }
-            }
-            // payout to contract owner - may be previous token owner -> then they get remainder of balance
-            if self.contract_royalty > 0 && self.owner_id != owner_id {
-                payout.insert(
-                    self.owner_id.clone(),
-                    royalty_to_payout(self.contract_royalty, balance_u128),
-                );
-                total_perpetual += self.contract_royalty;
-            }
-            // assert!(
-            //     total_perpetual <= MINTER_ROYALTY_CAP + CONTRACT_ROYALTY_CAP,
-            //     "Royalties should not be more than caps"
-            // );
-            // payout to previous owner
-            payout.insert(
-                owner_id,
-                royalty_to_payout(10000 - total_perpetual, balance_u128),
-            );
-
-            env::log(format!("total_perpetual {:?}", total_perpetual).as_bytes());
+        let payout = balance.map(|balance| {
+            self.internal_payout(&previous_token.owner_id, &token_id, u128::from(balance))
+        });
+        if let Some(payout) = payout.as_ref() {
             env::log(format!("Payouts {:?}", payout).as_bytes());
+        }
 
-            Some(payout)
-        } else {
-            None
-        };
+        payout
+    }
 
+    fn nft_payout(&self, token_id: TokenId, balance: U128, max_len_payout: u32) -> Payout {
+        let owner_id = self.tokens_by_id.get(&token_id).expect("No token").owner_id;
+        let payout = self.internal_payout(&owner_id, &token_id, balance.into());
+        assert!(
+            payout.len() <= max_len_payout as usize,
+            "Cannot payout to more than {} receivers",
+            max_len_payout
+        );
         payout
     }
 